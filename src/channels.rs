@@ -0,0 +1,275 @@
+use std::{fmt, sync::Arc};
+
+use poise::serenity_prelude::{
+    self as serenity, Channel, ChannelId, ChannelType, GuildId, Http, HttpError, Permissions,
+    StatusCode, UserId,
+};
+use tracing::{info, warn};
+
+use crate::{
+    mutations::set_misconfigured,
+    storage::Storage,
+    structs::{ApplicationState, RWGuildData},
+};
+
+/// Why a guild's birthdays cannot go to its announcement channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelProblem {
+    NotSet,
+    Deleted,
+    NoAccess,
+}
+
+impl fmt::Display for ChannelProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotSet => "no announcement channel has been set",
+            Self::Deleted => "the announcement channel was deleted",
+            Self::NoAccess => "I am not allowed to post in the announcement channel",
+        })
+    }
+}
+
+impl ChannelProblem {
+    /// Only errors that say the channel itself is the problem count, anything else is likely
+    /// to pass on its own
+    pub fn from_error(error: &serenity::Error) -> Option<Self> {
+        match error {
+            serenity::Error::Http(http_error) => match http_error.as_ref() {
+                HttpError::UnsuccessfulRequest(response) => match response.status_code {
+                    StatusCode::NOT_FOUND => Some(Self::Deleted),
+                    StatusCode::FORBIDDEN => Some(Self::NoAccess),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Whether the channel still exists, belongs to the guild and can be seen by the bot. The outer
+/// error is for a check that could not be made, such as when Discord is unreachable.
+pub async fn check_channel(
+    http: &Http,
+    guild_id: u64,
+    channel_id: Option<u64>,
+) -> serenity::Result<Result<(), ChannelProblem>> {
+    let channel_id = match channel_id {
+        Some(channel_id) => channel_id,
+        None => return Ok(Err(ChannelProblem::NotSet)),
+    };
+    match ChannelId(channel_id).to_channel(http).await {
+        Ok(Channel::Guild(channel)) if channel.guild_id.0 == guild_id => Ok(Ok(())),
+        Ok(_) => Ok(Err(ChannelProblem::NoAccess)),
+        Err(e) => match ChannelProblem::from_error(&e) {
+            Some(problem) => Ok(Err(problem)),
+            None => Err(e),
+        },
+    }
+}
+
+/// The guild's announcement channel, read without holding any lock afterwards
+async fn announcement_channel(state: &ApplicationState, guild_id: u64) -> Option<u64> {
+    let guild_data = Arc::clone(state.guild_map.read().await.get(&guild_id)?);
+    let channel = guild_data.rw_lock.read().await.announcement_channel;
+    channel
+}
+
+/// Marks the guild as misconfigured and tells its owner the first time, returns the system
+/// channel to announce in for now if the guild has one. No lock is held over the requests to
+/// Discord.
+pub async fn flag_misconfigured(
+    http: &Http,
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    problem: ChannelProblem,
+) -> Option<ChannelId> {
+    let broken = announcement_channel(state, guild_id).await;
+    let guild = match GuildId(guild_id).to_partial_guild(http).await {
+        Ok(guild) => Some(guild),
+        Err(e) => {
+            warn!(guild_id, error = %e, "Could not look up the guild to fall back on");
+            None
+        }
+    };
+    let fallback = guild
+        .as_ref()
+        .and_then(|guild| guild.system_channel_id)
+        .filter(|fallback| Some(fallback.0) != broken);
+
+    if !set_misconfigured(state, storage, guild_id, Some(problem.to_string())).await {
+        return fallback;
+    }
+    warn!(guild_id, %problem, ?fallback, "The announcement channel is misconfigured");
+
+    let guild = match guild {
+        Some(guild) => guild,
+        None => return fallback,
+    };
+    let instead = match fallback {
+        Some(fallback) => format!("Until then they go to <#{}>.", fallback),
+        None => "Until then they are not announced at all.".to_string(),
+    };
+    let notice = format!(
+        "I can't announce birthdays in **{}**: {}. Someone with Manage Server can fix this with /channel. {}",
+        guild.name, problem, instead
+    );
+    let sent = match guild.owner_id.create_dm_channel(http).await {
+        Ok(dm) => dm.say(http, notice).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = sent {
+        warn!(guild_id, error = %e, "Could not tell the guild owner about the announcement channel");
+    }
+    fallback
+}
+
+/// Clears the misconfigured flag once the channel works again
+pub async fn clear_misconfigured(state: &ApplicationState, storage: &dyn Storage, guild_id: u64) {
+    if set_misconfigured(state, storage, guild_id, None).await {
+        info!(guild_id, "The announcement channel works again");
+    }
+}
+
+/// Checks the announcement channel of every guild with birthdays to announce, run once at
+/// startup so a problem is found before a birthday is missed
+pub async fn check_all(http: Arc<Http>, state: Arc<ApplicationState>, storage: Arc<dyn Storage>) {
+    let shared: Vec<(u64, Arc<RWGuildData>)> = state
+        .guild_map
+        .read()
+        .await
+        .iter()
+        .map(|(guild_id, guild_data)| (*guild_id, Arc::clone(guild_data)))
+        .collect();
+    let mut guilds = Vec::with_capacity(shared.len());
+    for (guild_id, guild_data) in shared {
+        let guild_data = guild_data.rw_lock.read().await;
+        if guild_data.removed_at.is_none() && !guild_data.birthday_schedule.is_empty() {
+            guilds.push((guild_id, guild_data.announcement_channel));
+        }
+    }
+
+    let mut misconfigured = 0;
+    for (guild_id, channel_id) in guilds {
+        let result = match check_channel(&http, guild_id, channel_id).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!(guild_id, error = %e, "Could not check the announcement channel");
+                continue;
+            }
+        };
+        match result {
+            Ok(()) => clear_misconfigured(&state, storage.as_ref(), guild_id).await,
+            Err(problem) => {
+                misconfigured += 1;
+                flag_misconfigured(&http, &state, storage.as_ref(), guild_id, problem).await;
+            }
+        }
+    }
+    info!(misconfigured, "Checked the announcement channels");
+}
+
+/// Flags the guild whose announcement channel was just deleted
+pub async fn channel_deleted(
+    http: &Http,
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    channel_id: u64,
+) {
+    if announcement_channel(state, guild_id).await != Some(channel_id) {
+        return;
+    }
+    flag_misconfigured(http, state, storage, guild_id, ChannelProblem::Deleted).await;
+}
+
+/// Permissions announcing cannot do without
+const REQUIRED: [(Permissions, &str); 2] = [
+    (Permissions::VIEW_CHANNEL, "View Channel"),
+    (Permissions::SEND_MESSAGES, "Send Messages"),
+];
+
+/// Permissions a custom announcement may need, only warned about
+const WANTED: [(Permissions, &str); 2] = [
+    (Permissions::EMBED_LINKS, "Embed Links"),
+    (
+        Permissions::MENTION_EVERYONE,
+        "Mention @everyone, @here and All Roles",
+    ),
+];
+
+/// Checks a channel before it becomes the announcement channel: it has to be a text channel in
+/// this guild that the bot can post in, proven by posting a test message. Returns warnings about
+/// things that only some announcements need, or why the channel cannot be used.
+pub async fn validate_announcement_channel(
+    http: &Http,
+    guild_id: u64,
+    bot_id: UserId,
+    channel_id: ChannelId,
+) -> Result<Vec<String>, String> {
+    let channel = match channel_id.to_channel(http).await {
+        Ok(Channel::Guild(channel)) => channel,
+        Ok(_) => return Err("That is not a server channel".to_string()),
+        Err(e) => {
+            return Err(match ChannelProblem::from_error(&e) {
+                Some(_) => {
+                    "I can't see that channel, check that I have View Channel there".to_string()
+                }
+                None => format!("Could not look up that channel: {}", e),
+            })
+        }
+    };
+    if channel.guild_id.0 != guild_id {
+        return Err("That channel is in another server".to_string());
+    }
+    if !matches!(channel.kind, ChannelType::Text | ChannelType::News) {
+        return Err(format!(
+            "<#{}> is not a text or announcement channel, birthdays can't be posted there",
+            channel.id
+        ));
+    }
+
+    let mut warnings = Vec::new();
+    let permissions = match GuildId(guild_id).to_partial_guild(http).await {
+        Ok(guild) => match guild.member(http, bot_id).await {
+            Ok(member) => guild.user_permissions_in(&channel, &member).ok(),
+            Err(_) => None,
+        },
+        Err(_) => None,
+    };
+    match permissions {
+        Some(permissions) => {
+            let missing: Vec<&str> = REQUIRED
+                .iter()
+                .filter(|(permission, _)| !permissions.contains(*permission))
+                .map(|(_, name)| *name)
+                .collect();
+            if !missing.is_empty() {
+                return Err(format!(
+                    "I am missing {} in <#{}>",
+                    missing.join(", "),
+                    channel.id
+                ));
+            }
+            for (permission, name) in WANTED {
+                if !permissions.contains(permission) {
+                    warnings.push(format!(
+                        "I don't have {}, announcements that need it won't work fully",
+                        name
+                    ));
+                }
+            }
+        }
+        None => warnings.push("Could not work out my permissions in that channel".to_string()),
+    }
+
+    if let Err(e) = channel
+        .say(http, "Birthdays will be announced in this channel 🎂")
+        .await
+    {
+        return Err(format!("Could not post a test message there: {}", e));
+    }
+    Ok(warnings)
+}
@@ -1,12 +1,16 @@
 use poise::serenity_prelude::Member;
 
-use crate::structs::{Context, Error};
+use crate::mutations::{remove_birthday, MutationError};
+use crate::structs::{Context, Error, TRASH_RETENTION_DAYS};
 
 /// Delete a users birthday
 #[poise::command(slash_command)]
 pub async fn del(
     ctx: Context<'_>,
-    #[description = "The User you are deleting a birthday for"] user: Member,
+    #[description = "The User you are deleting a birthday for"] user: Option<Member>,
+    #[description = "The name of an imported birthday that is not linked to a member"] name: Option<
+        String,
+    >,
 ) -> Result<(), Error> {
     let guild_id = match ctx.guild_id() {
         Some(id) => id.0,
@@ -16,26 +20,56 @@ pub async fn del(
             return Ok(());
         }
     };
-    let data = ctx.data().state.guild_map.read().await;
-    match data.get(&guild_id) {
-        Some(guild_data) => {
-            let mut guild_writer = guild_data.rw_lock.write().await;
 
-            let deletion = guild_writer.birthday_schedule.remove(&user.user.id.0);
-
-            if deletion.is_some() {
-                ctx.data().saver.save();
-                ctx.say("Birthday removed successfully").await?;
-            } else {
-                ctx.say("User is not registered with the birthday service")
-                    .await?;
+    let user_id = match (user, name) {
+        (Some(user), None) => user.user.id.0,
+        (None, Some(name)) => {
+            let reader = ctx.data().state.guild_map.read().await;
+            let found = match reader.get(&guild_id) {
+                Some(guild_data) => guild_data
+                    .rw_lock
+                    .read()
+                    .await
+                    .birthday_schedule
+                    .find_unlinked(&name)
+                    .map(|info| info.associated_user),
+                None => None,
+            };
+            match found {
+                Some(user_id) => user_id,
+                None => {
+                    ctx.say(MutationError::NotRegistered.to_string()).await?;
+                    return Ok(());
+                }
             }
+        }
+        _ => {
+            ctx.say("Give either a user or a name, not both or neither")
+                .await?;
+            return Ok(());
+        }
+    };
 
-            Ok(())
+    match remove_birthday(
+        &ctx.data().state,
+        ctx.data().storage.as_ref(),
+        guild_id,
+        user_id,
+        ctx.author().id.0,
+    )
+    .await
+    {
+        Ok(_) => {
+            ctx.say(format!(
+                "Birthday removed successfully (use `/bday undo` within {} days to bring it back)",
+                TRASH_RETENTION_DAYS
+            ))
+            .await?;
         }
-        None => {
-            ctx.say("No birthdays found in this server").await?;
-            Ok(())
+        Err(e) => {
+            ctx.say(e.to_string()).await?;
         }
     }
+
+    Ok(())
 }
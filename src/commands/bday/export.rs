@@ -0,0 +1,170 @@
+use std::{borrow::Cow, sync::Arc};
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use poise::serenity_prelude::AttachmentType;
+
+use crate::{
+    ical::{write_calendar, CalendarEntry},
+    names::MemberName,
+    records::{write_csv, write_json, BirthdayRecord},
+    structs::{BirthdayInfo, Context, Error},
+};
+
+/// Export this server's birthdays as a file
+#[poise::command(slash_command, subcommands("ics", "csv", "json"))]
+pub async fn export(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.say("Use one of the subcommands to pick an export format")
+        .await?;
+    Ok(())
+}
+
+/// Collects the birthdays that may be exported, replying to the user if there are none
+async fn exportable_entries(
+    ctx: Context<'_>,
+) -> Result<Option<(u64, Vec<Arc<BirthdayInfo>>, Option<Tz>)>, Error> {
+    let guild_id = match ctx.guild_id() {
+        Some(id) => id.0,
+        None => {
+            ctx.say("Only works inside servers (sorry lonely loser)")
+                .await?;
+            return Ok(None);
+        }
+    };
+
+    let (entries, guild_timezone) = {
+        let reader = ctx.data().state.guild_map.read().await;
+        let data = match reader.get(&guild_id) {
+            Some(data) => data.rw_lock.read().await,
+            None => {
+                ctx.say("This server has no birthdays").await?;
+                return Ok(None);
+            }
+        };
+        let entries = data
+            .birthday_schedule
+            .ordered_iter()
+            .filter(|info| !info.private)
+            .map(Arc::clone)
+            .collect::<Vec<_>>();
+        (entries, data.timezone)
+    };
+
+    if entries.is_empty() {
+        ctx.say("This server has no birthdays that can be exported")
+            .await?;
+        return Ok(None);
+    }
+
+    Ok(Some((guild_id, entries, guild_timezone)))
+}
+
+async fn send_file(
+    ctx: Context<'_>,
+    message: String,
+    contents: String,
+    filename: &str,
+) -> Result<(), Error> {
+    ctx.send(|b| {
+        b.content(message).attachment(AttachmentType::Bytes {
+            data: Cow::Owned(contents.into_bytes()),
+            filename: filename.to_string(),
+        })
+    })
+    .await?;
+    Ok(())
+}
+
+/// Export birthdays as an iCalendar file with yearly events
+#[poise::command(slash_command)]
+pub async fn ics(ctx: Context<'_>) -> Result<(), Error> {
+    let (guild_id, entries, guild_timezone) = match exportable_entries(ctx).await? {
+        Some(exportable) => exportable,
+        None => return Ok(()),
+    };
+
+    let names = ctx
+        .data()
+        .names
+        .resolve_entries(
+            &ctx.serenity_context().http,
+            guild_id,
+            entries.iter().map(|info| info.as_ref()),
+        )
+        .await;
+
+    let calendar_entries = entries
+        .iter()
+        .filter_map(|info| match &names[&info.associated_user] {
+            // People who left the server are no longer part of its calendar
+            MemberName::Departed => None,
+            name => CalendarEntry::for_birthday(guild_id, info, guild_timezone, name.display()),
+        })
+        .collect::<Vec<_>>();
+
+    let guild_name = match ctx.partial_guild().await {
+        Some(guild) => guild.name,
+        None => "Server".to_string(),
+    };
+
+    let calendar = write_calendar(
+        &format!("{} birthdays", guild_name),
+        &calendar_entries,
+        Utc::now(),
+    );
+
+    send_file(
+        ctx,
+        format!(
+            "Exported {} birthdays, import the file into your calendar app",
+            calendar_entries.len()
+        ),
+        calendar,
+        "birthdays.ics",
+    )
+    .await
+}
+
+/// Export birthdays as a CSV file with user, date, timezone and year columns
+#[poise::command(slash_command)]
+pub async fn csv(ctx: Context<'_>) -> Result<(), Error> {
+    let (_, entries, guild_timezone) = match exportable_entries(ctx).await? {
+        Some(exportable) => exportable,
+        None => return Ok(()),
+    };
+
+    let records = entries
+        .iter()
+        .map(|info| BirthdayRecord::from_info(info, guild_timezone))
+        .collect::<Vec<_>>();
+
+    send_file(
+        ctx,
+        format!("Exported {} birthdays", records.len()),
+        write_csv(&records)?,
+        "birthdays.csv",
+    )
+    .await
+}
+
+/// Export birthdays as a JSON file with user, date, timezone and year fields
+#[poise::command(slash_command)]
+pub async fn json(ctx: Context<'_>) -> Result<(), Error> {
+    let (_, entries, guild_timezone) = match exportable_entries(ctx).await? {
+        Some(exportable) => exportable,
+        None => return Ok(()),
+    };
+
+    let records = entries
+        .iter()
+        .map(|info| BirthdayRecord::from_info(info, guild_timezone))
+        .collect::<Vec<_>>();
+
+    send_file(
+        ctx,
+        format!("Exported {} birthdays", records.len()),
+        write_json(&records)?,
+        "birthdays.json",
+    )
+    .await
+}
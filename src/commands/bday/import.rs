@@ -0,0 +1,307 @@
+use std::{sync::Arc, time::Duration};
+
+use poise::serenity_prelude::{
+    self as serenity, Attachment, ButtonStyle, CollectComponentInteraction,
+};
+
+use crate::{
+    ical::read_calendar,
+    import::{plan_import, resolve_rows, ImportOutcome, ImportPlan, MAX_IMPORT_BYTES},
+    mutations::apply_import,
+    records::{read_csv, read_json},
+    structs::{Context, Error, GuildData},
+    vcard::read_vcards,
+};
+
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(300);
+/// How many rows of each kind are spelled out in the preview before summarising the rest
+const MAX_LISTED_ROWS: usize = 10;
+/// Rows stop being listed past this length, which leaves room under Discord's 2000 character
+/// limit for the summary lines and the outcome added once the import is applied
+const MAX_PREVIEW_LEN: usize = 1500;
+
+/// Import birthdays from a CSV, JSON, vCard or iCalendar file, showing a preview first
+#[poise::command(slash_command, required_permissions = "MANAGE_GUILD")]
+pub async fn import(
+    ctx: Context<'_>,
+    #[description = "A .csv/.json file (user, date, timezone, year), contacts (.vcf) or a calendar (.ics)"]
+    file: Attachment,
+) -> Result<(), Error> {
+    let guild_id = match ctx.guild_id() {
+        Some(id) => id.0,
+        None => {
+            ctx.say("Only works inside servers (sorry lonely loser)")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if file.size > MAX_IMPORT_BYTES {
+        ctx.say(format!(
+            "That file is too big, imports are limited to {} KiB",
+            MAX_IMPORT_BYTES / 1024
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    // Downloading and matching members can take longer than Discord waits for a reply
+    ctx.defer().await?;
+
+    let bytes = file.download().await?;
+    let filename = file.filename.to_lowercase();
+
+    // Contacts and calendars are matched by name, anyone without a match is added on their own
+    let (rows, allow_unlinked) = if filename.ends_with(".csv") {
+        (read_csv(&bytes), false)
+    } else if filename.ends_with(".json") {
+        match read_json(&bytes) {
+            Ok(rows) => (rows, false),
+            Err(e) => {
+                ctx.say(e).await?;
+                return Ok(());
+            }
+        }
+    } else if filename.ends_with(".vcf") || filename.ends_with(".vcard") {
+        (read_vcards(&String::from_utf8_lossy(&bytes)), true)
+    } else if filename.ends_with(".ics") {
+        (read_calendar(&String::from_utf8_lossy(&bytes)), true)
+    } else {
+        ctx.say("Only .csv, .json, .vcf and .ics files can be imported")
+            .await?;
+        return Ok(());
+    };
+
+    let resolved = resolve_rows(
+        &ctx.serenity_context().http,
+        &ctx.data().names,
+        guild_id,
+        rows,
+        allow_unlinked,
+    )
+    .await;
+
+    let plan = {
+        let reader = ctx.data().state.guild_map.read().await;
+        match reader.get(&guild_id) {
+            Some(data) => plan_import(&*data.rw_lock.read().await, resolved),
+            None => plan_import(&GuildData::default(), resolved),
+        }
+    };
+
+    confirm_and_apply(ctx, guild_id, plan).await
+}
+
+fn render_preview(plan: &ImportPlan) -> String {
+    let mut res = format!(
+        "**Import preview** (nothing has been changed yet)\n{} new, {} changed, {} unchanged, {} invalid\n",
+        plan.new.len(),
+        plan.conflicts.len(),
+        plan.unchanged,
+        plan.invalid.len()
+    );
+
+    let unlinked = plan.new_unlinked();
+    if unlinked > 0 {
+        res += format!(
+            "\n{} of the new birthdays do not match a member and will be added by name:\n",
+            unlinked
+        )
+        .as_str();
+        let unlinked_rows = plan.new.iter().filter(|planned| !planned.info.is_linked());
+        push_rows(
+            &mut res,
+            unlinked,
+            unlinked_rows.map(|planned| (planned.row, planned.label.as_str())),
+        );
+    }
+
+    if !plan.conflicts.is_empty() {
+        res += "\nAlready registered with a different birthday:\n";
+        push_rows(
+            &mut res,
+            plan.conflicts.len(),
+            plan.conflicts
+                .iter()
+                .map(|planned| (planned.row, planned.label.as_str())),
+        );
+    }
+
+    if !plan.invalid.is_empty() {
+        res += "\nInvalid rows (these will be skipped):\n";
+        push_rows(
+            &mut res,
+            plan.invalid.len(),
+            plan.invalid
+                .iter()
+                .map(|(row, reason)| (*row, reason.as_str())),
+        );
+    }
+    res
+}
+
+/// Lists rows until there are MAX_LISTED_ROWS of them or the preview would get too long for a
+/// message, then sums up the rest
+fn push_rows<'a>(res: &mut String, total: usize, rows: impl Iterator<Item = (usize, &'a str)>) {
+    let mut listed = 0;
+    for (row, text) in rows.take(MAX_LISTED_ROWS) {
+        let line = format!("- Row {}: {}\n", row, text);
+        if res.len() + line.len() > MAX_PREVIEW_LEN {
+            break;
+        }
+        *res += &line;
+        listed += 1;
+    }
+    if total > listed {
+        *res += &format!("- ...and {} more\n", total - listed);
+    }
+}
+
+fn describe_outcome(outcome: &ImportOutcome) -> String {
+    let mut res = format!("Imported {} birthdays", outcome.applied);
+    if outcome.skipped > 0 {
+        res += &format!(
+            ", skipped {} that were set to something else since this preview",
+            outcome.skipped
+        );
+    }
+    if outcome.no_free_id > 0 {
+        res += &format!(
+            ", could not add {} by name as this server has no room for more",
+            outcome.no_free_id
+        );
+    }
+    res
+}
+
+/// Shows the preview and waits for the invoking user to apply or cancel it
+pub async fn confirm_and_apply(
+    ctx: Context<'_>,
+    guild_id: u64,
+    plan: ImportPlan,
+) -> Result<(), Error> {
+    let preview = render_preview(&plan);
+
+    if plan.new.is_empty() && plan.conflicts.is_empty() {
+        ctx.say(preview + "\nThere is nothing to import.").await?;
+        return Ok(());
+    }
+
+    let ctx_id = ctx.id();
+    let apply_id = format!("{}apply", ctx_id);
+    let keep_id = format!("{}keep", ctx_id);
+    let cancel_id = format!("{}cancel", ctx_id);
+    let author_id = ctx.author().id;
+    let has_conflicts = !plan.conflicts.is_empty();
+
+    let reply = ctx
+        .send(|b| {
+            b.content(&preview).components(|b| {
+                b.create_action_row(|b| {
+                    b.create_button(|b| {
+                        b.custom_id(&apply_id)
+                            .style(ButtonStyle::Success)
+                            .label(if has_conflicts {
+                                "Apply and overwrite"
+                            } else {
+                                "Apply"
+                            })
+                    });
+                    if has_conflicts {
+                        b.create_button(|b| {
+                            b.custom_id(&keep_id)
+                                .style(ButtonStyle::Primary)
+                                .label("Apply, keep existing")
+                        });
+                    }
+                    b.create_button(|b| {
+                        b.custom_id(&cancel_id)
+                            .style(ButtonStyle::Danger)
+                            .label("Cancel")
+                    })
+                })
+            })
+        })
+        .await?;
+
+    let mut outcome = "Import timed out, nothing was changed".to_string();
+
+    let button_ids = Arc::new([apply_id.clone(), keep_id.clone(), cancel_id.clone()]);
+    while let Some(press) = CollectComponentInteraction::new(ctx)
+        .filter({
+            let button_ids = Arc::clone(&button_ids);
+            move |press| button_ids.contains(&press.data.custom_id)
+        })
+        .timeout(CONFIRMATION_TIMEOUT)
+        .await
+    {
+        if press.user.id != author_id {
+            press
+                .create_interaction_response(ctx, |b| {
+                    b.kind(serenity::InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|b| {
+                            b.content("Only the person who started the import can confirm it")
+                                .ephemeral(true)
+                        })
+                })
+                .await?;
+            continue;
+        }
+
+        let overwrite = if press.data.custom_id == apply_id {
+            true
+        } else if press.data.custom_id == keep_id {
+            false
+        } else {
+            outcome = "Import cancelled, nothing was changed".to_string();
+            press.defer(ctx).await?;
+            break;
+        };
+
+        let applied = apply_import(
+            &ctx.data().state,
+            ctx.data().storage.as_ref(),
+            guild_id,
+            &plan,
+            overwrite,
+        )
+        .await;
+
+        // The buttons are taken away below whatever happened, so a failure is reported there
+        outcome = match applied {
+            Ok(applied) => describe_outcome(&applied),
+            Err(e) => format!("Import failed: {}", e),
+        };
+        press.defer(ctx).await?;
+        break;
+    }
+
+    reply
+        .edit(ctx, |b| {
+            b.content(format!("{}\n**{}**", preview, outcome))
+                .components(|b| b)
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_preview_of_many_long_rows_fits_in_a_message() {
+        let plan = ImportPlan {
+            invalid: (1..=500)
+                .map(|row| (row, format!("No member named `{}`", "x".repeat(200))))
+                .collect(),
+            ..Default::default()
+        };
+        let preview = render_preview(&plan);
+        assert!(preview.len() <= 2000 - 200, "{}", preview.len());
+        let listed = preview.matches("- Row ").count();
+        assert!(listed > 0);
+        assert!(preview.ends_with(&format!("- ...and {} more\n", 500 - listed)));
+    }
+}
@@ -1,11 +1,11 @@
-use poise::serenity_prelude::{GuildId, UserId};
+use std::sync::Arc;
 
+use crate::commands::paginate::{build_pages, paginate};
 use crate::structs::{Context, Error};
 
 /// List all birthdays on the server
 #[poise::command(slash_command)]
 pub async fn list(ctx: Context<'_>) -> Result<(), Error> {
-    let mut res = "Birthdays:\n".to_string();
     let guild_id = match ctx.guild_id() {
         Some(id) => id.0,
         None => {
@@ -14,46 +14,57 @@ pub async fn list(ctx: Context<'_>) -> Result<(), Error> {
             return Ok(());
         }
     };
-    let reader = ctx.data().state.guild_map.read().await;
-    let data = match reader.get(&guild_id) {
-        Some(data) => data.rw_lock.read().await,
-        None => {
-            ctx.say("This server has no birthdays").await?;
-            return Ok(());
-        }
-    };
 
-    let birthday_map = &data.birthday_schedule;
+    // Copy the entries out so the locks are not held while names are fetched and pages flipped
+    let (entries, guild_timezone) = {
+        let reader = ctx.data().state.guild_map.read().await;
+        let data = match reader.get(&guild_id) {
+            Some(data) => data.rw_lock.read().await,
+            None => {
+                ctx.say("This server has no birthdays").await?;
+                return Ok(());
+            }
+        };
+        let entries = data
+            .birthday_schedule
+            .ordered_iter()
+            .map(Arc::clone)
+            .collect::<Vec<_>>();
+        (entries, data.timezone)
+    };
 
-    if birthday_map.is_empty() {
+    if entries.is_empty() {
         ctx.say("This server has no birthdays").await?;
         return Ok(());
     }
 
+    let names = ctx
+        .data()
+        .names
+        .resolve_entries(
+            &ctx.serenity_context().http,
+            guild_id,
+            entries.iter().map(|info| info.as_ref()),
+        )
+        .await;
+
     let mut bold_char = "**";
     let mut postfix = " (nearest birthday)";
+    let mut lines = Vec::with_capacity(entries.len());
 
-    for info in birthday_map.ordered_iter() {
-        let user_str = match GuildId(guild_id)
-            .member(ctx, UserId(info.associated_user))
-            .await
-        {
-            Ok(user) => user.display_name().to_string(),
-            Err(_) => "UserFetchError".to_string(),
-        };
-
-        res += format!(
-            "- {b}{}'s birthday is on {}{b}{p}\n",
-            user_str,
-            info.datetime.format("%B %e"),
+    for info in entries.iter() {
+        lines.push(format!(
+            "- {b}{}'s birthday is on {}{b}{p}",
+            names[&info.associated_user].display(),
+            info.local_date(guild_timezone).format("%B %e"),
             b = bold_char,
             p = postfix
-        )
-        .as_str();
+        ));
 
         bold_char = "";
         postfix = "";
     }
-    ctx.say(res).await?;
-    Ok(())
+
+    let pages = build_pages("Birthdays:\n", &lines);
+    paginate(ctx, &pages).await
 }
@@ -1,18 +1,33 @@
 use self::del::del;
+use self::export::export;
 use self::get::get;
+use self::import::import;
 use self::list::list;
+use self::restore::restore;
 use self::set::set;
 use self::today::today;
+use self::undo::undo;
+use self::upcoming::upcoming;
 use crate::structs::{Context, Error};
 
 mod del;
+mod export;
 mod get;
+mod import;
 mod list;
+mod restore;
 mod set;
 mod today;
+mod undo;
+mod upcoming;
 
 /// Parent Command for all birthdat relayed doodads
-#[poise::command(slash_command, subcommands("set", "del", "list", "get", "today"))]
+#[poise::command(
+    slash_command,
+    subcommands(
+        "set", "del", "list", "get", "today", "undo", "restore", "upcoming", "export", "import"
+    )
+)]
 pub async fn bday(ctx: Context<'_>) -> Result<(), Error> {
     ctx.say("Use one of the subcommands to alter this guilds birthday list")
         .await?;
@@ -0,0 +1,62 @@
+use poise::serenity_prelude::Member;
+
+use crate::{
+    mutations::{restore_removed, MutationError},
+    structs::{Context, Error},
+};
+
+/// Restore a recently removed birthday
+#[poise::command(slash_command)]
+pub async fn restore(
+    ctx: Context<'_>,
+    #[description = "The User whose removed birthday you are restoring"] user: Member,
+) -> Result<(), Error> {
+    let guild_id = match ctx.guild_id() {
+        Some(id) => id.0,
+        None => {
+            ctx.say("Only works inside servers (sorry lonely loser)")
+                .await?;
+            return Ok(());
+        }
+    };
+    let restored = restore_removed(
+        &ctx.data().state,
+        ctx.data().storage.as_ref(),
+        guild_id,
+        user.user.id.0,
+    )
+    .await;
+    let restored = match restored {
+        Ok(restored) => restored,
+        Err(MutationError::NothingToRestore) => {
+            ctx.say(format!(
+                "{} has no recently removed birthday",
+                user.display_name()
+            ))
+            .await?;
+            return Ok(());
+        }
+        Err(MutationError::AlreadyRegistered(_)) => {
+            ctx.say(format!(
+                "{} already has a birthday registered",
+                user.display_name()
+            ))
+            .await?;
+            return Ok(());
+        }
+        Err(e) => {
+            ctx.say(e.to_string()).await?;
+            return Ok(());
+        }
+    };
+
+    let guild_timezone = ctx.data().state.guild_timezone(guild_id).await;
+    ctx.say(format!(
+        "Restored the birthday of {} on {}",
+        user.display_name(),
+        restored.local_date(guild_timezone).format("%B %e")
+    ))
+    .await?;
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
+use std::sync::Arc;
+
 use chrono::{DateTime, Days, Utc};
-use poise::serenity_prelude::{GuildId, UserId};
 
 use crate::structs::{Context, Error};
 
@@ -15,57 +16,67 @@ pub async fn today(ctx: Context<'_>) -> Result<(), Error> {
             return Ok(());
         }
     };
-    let reader = ctx.data().state.guild_map.read().await;
-    let data = match reader.get(&guild_id) {
-        Some(data) => data.rw_lock.read().await,
-        None => {
+
+    // Pick out today's birthdays first so no lock is held while fetching names
+    let todays_users = {
+        let reader = ctx.data().state.guild_map.read().await;
+        let data = match reader.get(&guild_id) {
+            Some(data) => data.rw_lock.read().await,
+            None => {
+                ctx.say("This server has no birthdays").await?;
+                return Ok(());
+            }
+        };
+
+        let birthday_map = &data.birthday_schedule;
+
+        if birthday_map.is_empty() {
             ctx.say("This server has no birthdays").await?;
             return Ok(());
         }
-    };
 
-    let birthday_map = &data.birthday_schedule;
-
-    if birthday_map.is_empty() {
-        ctx.say("This server has no birthdays").await?;
-        return Ok(());
-    }
+        let mut todays_users = vec![];
 
-    let mut count = 0;
+        for info in birthday_map.ordered_iter() {
+            let eod = info.datetime.checked_add_days(Days::new(1));
+            let eod = match eod {
+                Some(eod) => DateTime::with_timezone(&eod, &Utc),
+                None => {
+                    ctx.say("Could not calculate EOD for timekeeping measures")
+                        .await?;
+                    return Ok(());
+                }
+            };
 
-    for info in birthday_map.ordered_iter() {
-        let user_str = match GuildId(guild_id)
-            .member(ctx, UserId(info.associated_user))
-            .await
-        {
-            Ok(user) => user.display_name().to_string(),
-            Err(_) => "UserFetchError".to_string(),
-        };
-
-        let eod = info.datetime.checked_add_days(Days::new(1));
-        let eod = match eod {
-            Some(eod) => DateTime::with_timezone(&eod, &Utc),
-            None => {
-                ctx.say("Could not calculate EOD for timekeeping measures")
-                    .await?;
-                return Ok(());
+            if !(info.datetime < Utc::now() && Utc::now() < eod) {
+                continue;
             }
-        };
 
-        if !(info.datetime < Utc::now() && Utc::now() < eod) {
-            continue;
+            todays_users.push(Arc::clone(info));
         }
+        todays_users
+    };
 
-        res += format!("- {}\n", user_str,).as_str();
-
-        count += 1;
-    }
-    if count > 0 {
-        ctx.say(res).await?;
-    } else {
+    if todays_users.is_empty() {
         ctx.say("None of the registered birthdays are today.")
             .await?;
+        return Ok(());
+    }
+
+    let names = ctx
+        .data()
+        .names
+        .resolve_entries(
+            &ctx.serenity_context().http,
+            guild_id,
+            todays_users.iter().map(|info| info.as_ref()),
+        )
+        .await;
+
+    for info in todays_users {
+        res += format!("- {}\n", names[&info.associated_user].display()).as_str();
     }
+    ctx.say(res).await?;
 
     Ok(())
 }
@@ -0,0 +1,62 @@
+use crate::{
+    mutations::{undo_removal, MutationError},
+    structs::{BirthdayInfo, Context, Error},
+};
+
+fn who(info: &BirthdayInfo) -> String {
+    match &info.name {
+        Some(name) => name.clone(),
+        None => format!("<@{}>", info.associated_user),
+    }
+}
+
+/// Bring back the last birthday you removed
+#[poise::command(slash_command)]
+pub async fn undo(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = match ctx.guild_id() {
+        Some(id) => id.0,
+        None => {
+            ctx.say("Only works inside servers (sorry lonely loser)")
+                .await?;
+            return Ok(());
+        }
+    };
+    let restored = undo_removal(
+        &ctx.data().state,
+        ctx.data().storage.as_ref(),
+        guild_id,
+        ctx.author().id.0,
+    )
+    .await;
+    let restored = match restored {
+        Ok(restored) => restored,
+        Err(MutationError::NothingToRestore) => {
+            ctx.say("You have not removed any birthdays recently")
+                .await?;
+            return Ok(());
+        }
+        Err(MutationError::AlreadyRegistered(trashed)) => {
+            // The newer entry stays and so does the trashed copy
+            ctx.say(format!(
+                "{} already has a birthday registered again, remove it first to restore the old one",
+                who(&trashed.info)
+            ))
+            .await?;
+            return Ok(());
+        }
+        Err(e) => {
+            ctx.say(e.to_string()).await?;
+            return Ok(());
+        }
+    };
+
+    let guild_timezone = ctx.data().state.guild_timezone(guild_id).await;
+    ctx.say(format!(
+        "Restored the birthday of {} on {}",
+        who(&restored),
+        restored.local_date(guild_timezone).format("%B %e")
+    ))
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use chrono::{Duration, Utc};
+
+use crate::structs::{describe_days_until, Context, Error};
+
+const DEFAULT_WINDOW_DAYS: u32 = 30;
+const DEFAULT_LIMIT: u32 = 10;
+
+/// List the birthdays coming up soon
+#[poise::command(slash_command)]
+pub async fn upcoming(
+    ctx: Context<'_>,
+    #[description = "How many days ahead to look (defaults to 30)"]
+    #[min = 1]
+    #[max = 366]
+    days: Option<u32>,
+    #[description = "The most birthdays to show (defaults to 10)"]
+    #[min = 1]
+    #[max = 50]
+    limit: Option<u32>,
+) -> Result<(), Error> {
+    let days = days.unwrap_or(DEFAULT_WINDOW_DAYS);
+    let limit = limit.unwrap_or(DEFAULT_LIMIT) as usize;
+
+    let guild_id = match ctx.guild_id() {
+        Some(id) => id.0,
+        None => {
+            ctx.say("Only works inside servers (sorry lonely loser)")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let now = Utc::now();
+
+    // Work out everything needed from the schedule up front so no lock is held while fetching names
+    let upcoming = {
+        let reader = ctx.data().state.guild_map.read().await;
+        let data = match reader.get(&guild_id) {
+            Some(data) => data.rw_lock.read().await,
+            None => {
+                ctx.say("This server has no birthdays").await?;
+                return Ok(());
+            }
+        };
+
+        data.upcoming(now, Duration::days(days.into()))
+            .take(limit)
+            .collect::<Vec<_>>()
+    };
+
+    if upcoming.is_empty() {
+        ctx.say(format!("No birthdays in the next {} days", days))
+            .await?;
+        return Ok(());
+    }
+
+    let names = ctx
+        .data()
+        .names
+        .resolve_entries(
+            &ctx.serenity_context().http,
+            guild_id,
+            upcoming.iter().map(|(info, _, _)| info.as_ref()),
+        )
+        .await;
+
+    let mut res = format!("Birthdays in the next {} days:\n", days);
+
+    for (info, local_date, days_until) in upcoming {
+        res += format!(
+            "- {} on {} ({})\n",
+            names[&info.associated_user].display(),
+            local_date.format("%B %e"),
+            describe_days_until(days_until)
+        )
+        .as_str();
+    }
+
+    ctx.say(res).await?;
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use crate::{
+    mutations::set_departure_policy,
+    structs::{Context, DeparturePolicy, Error},
+};
+
+/// Choose what happens to birthdays when members leave or the bot is removed
+#[poise::command(slash_command, ephemeral)]
+pub async fn departures(
+    ctx: Context<'_>,
+    #[description = "What to do with the birthdays of members who leave (defaults to showing it)"]
+    policy: Option<DeparturePolicy>,
+) -> Result<(), Error> {
+    let guild_id = match ctx.guild_id() {
+        Some(id) => id.0,
+        None => {
+            ctx.say("Only works inside servers (sorry lonely loser)")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let policy = set_departure_policy(
+        &ctx.data().state,
+        ctx.data().storage.as_ref(),
+        guild_id,
+        policy,
+    )
+    .await?;
+
+    let mut reply = format!(
+        "When members leave: {}\nThis also decides whether the servers birthdays are kept if the bot is removed, everything except delete keeps them",
+        policy.name()
+    );
+    if !ctx.data().track_members {
+        reply +=
+            "\nThis bot is not set up to see members leave, so only the bot being removed counts";
+    }
+    ctx.say(reply).await?;
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use crate::{
+    mutations::{disable_feeds, feed_token},
+    structs::{Context, Error},
+    web::feeds::feed_urls,
+};
+
+#[derive(poise::ChoiceParameter)]
+pub enum FeedAction {
+    #[name = "Show the feed links"]
+    Show,
+    #[name = "Replace the links, breaking the old ones"]
+    Rotate,
+    #[name = "Turn the feeds off"]
+    Disable,
+}
+
+/// Get links to a live calendar and RSS feed of this servers birthdays
+#[poise::command(slash_command, ephemeral)]
+pub async fn feed(
+    ctx: Context<'_>,
+    #[description = "What to do with the feed links (defaults to showing them)"] action: Option<
+        FeedAction,
+    >,
+) -> Result<(), Error> {
+    let guild_id = match ctx.guild_id() {
+        Some(id) => id.0,
+        None => {
+            ctx.say("Only works inside servers (sorry lonely loser)")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let base_url = match &ctx.data().feed_base_url {
+        Some(base_url) => base_url,
+        None => {
+            ctx.say("Feeds are not enabled on this bot").await?;
+            return Ok(());
+        }
+    };
+
+    let state = &ctx.data().state;
+    let storage = ctx.data().storage.as_ref();
+    let token = match action.unwrap_or(FeedAction::Show) {
+        FeedAction::Disable => {
+            disable_feeds(state, storage, guild_id).await?;
+            ctx.say("Feeds turned off, the old links no longer work")
+                .await?;
+            return Ok(());
+        }
+        FeedAction::Rotate => feed_token(state, storage, guild_id, true).await?,
+        FeedAction::Show => feed_token(state, storage, guild_id, false).await?,
+    };
+
+    let (calendar_url, rss_url) = feed_urls(base_url, guild_id, &token);
+    ctx.say(format!(
+        "Anyone with these links can see the servers (non private) birthdays, keep them to yourself\nCalendar: <{}>\nRSS: <{}>",
+        calendar_url, rss_url
+    ))
+    .await?;
+
+    Ok(())
+}
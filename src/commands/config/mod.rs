@@ -0,0 +1,17 @@
+use self::{departures::departures, feed::feed};
+use crate::structs::{Context, Error};
+
+mod departures;
+mod feed;
+
+/// Parent Command for server settings
+#[poise::command(
+    slash_command,
+    subcommands("feed", "departures"),
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn config(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.say("Use one of the subcommands to change this servers settings")
+        .await?;
+    Ok(())
+}
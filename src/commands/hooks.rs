@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+use ::serenity::gateway::ConnectionStage;
+use poise::{serenity_prelude as serenity, BoxFuture, Event, FrameworkContext, FrameworkError};
+use tracing::{error, info, info_span, warn, Span};
+
+use crate::{
+    channels, mutations,
+    shutdown::CommandGuard,
+    structs::{Context, Data, Error},
+};
+
+/// Kept for the length of a command so the later hooks can time it and log in the same span
+struct Invocation {
+    started: Instant,
+    span: Span,
+    /// Holds shutdown back until the command is done, dropped along with the invocation
+    _running: CommandGuard,
+}
+
+pub fn pre_command(ctx: Context<'_>) -> BoxFuture<'_, ()> {
+    Box::pin(async move {
+        let span = info_span!(
+            "command",
+            command = %ctx.command().qualified_name,
+            guild_id = ctx.guild_id().map(|id| id.0),
+            user_id = ctx.author().id.0,
+        );
+        span.in_scope(|| info!("Command started"));
+        ctx.set_invocation_data(Invocation {
+            started: Instant::now(),
+            span,
+            _running: ctx.data().shutdown.command_started(),
+        })
+        .await;
+    })
+}
+
+/// How long the command took and the span it runs in, if the pre command hook got to run
+async fn finish_invocation(ctx: Context<'_>) -> (Option<Duration>, Span) {
+    match ctx.invocation_data::<Invocation>().await {
+        Some(invocation) => (Some(invocation.started.elapsed()), invocation.span.clone()),
+        None => (None, Span::none()),
+    }
+}
+
+pub fn post_command(ctx: Context<'_>) -> BoxFuture<'_, ()> {
+    Box::pin(async move {
+        let (elapsed, span) = finish_invocation(ctx).await;
+        span.in_scope(|| info!(?elapsed, "Command finished"));
+        ctx.data()
+            .metrics
+            .record_command(&ctx.command().qualified_name, "success", elapsed);
+    })
+}
+
+/// Turns commands away once the bot has started shutting down
+pub fn command_check(ctx: Context<'_>) -> BoxFuture<'_, Result<bool, Error>> {
+    Box::pin(async move {
+        if !ctx.data().shutdown.is_triggered() {
+            return Ok(true);
+        }
+        ctx.say("The bot is restarting, try again in a minute")
+            .await?;
+        Ok(false)
+    })
+}
+
+/// Counts and logs failed commands before handing the error to poise's default handler
+pub fn on_error(error: FrameworkError<'_, Data, Error>) -> BoxFuture<'_, ()> {
+    Box::pin(async move {
+        // A failed check without an error is a command turned away for shutdown, already answered
+        if let FrameworkError::CommandCheckFailed { error: None, .. } = &error {
+            return;
+        }
+        if let FrameworkError::Command { ctx, error } = &error {
+            let (elapsed, span) = finish_invocation(*ctx).await;
+            span.in_scope(|| error!(?elapsed, error = %error, "Command failed"));
+            ctx.data()
+                .metrics
+                .record_command(&ctx.command().qualified_name, "error", elapsed);
+        }
+        if let Err(e) = poise::builtins::on_error(error).await {
+            error!(error = %e, "Error while handling error");
+        }
+    })
+}
+
+/// Keeps the gateway status used by the readiness check up to date, follows the bot and
+/// members coming and going and notices a deleted announcement channel
+pub fn event_handler<'a>(
+    ctx: &'a serenity::Context,
+    event: &'a Event<'a>,
+    _framework: FrameworkContext<'a, Data, Error>,
+    data: &'a Data,
+) -> BoxFuture<'a, Result<(), Error>> {
+    Box::pin(async move {
+        match event {
+            Event::Ready { data_about_bot } => {
+                data.health.set_gateway_connected(true);
+                // A new session lists every guild the bot is in, including ones in an outage
+                let present = data_about_bot
+                    .guilds
+                    .iter()
+                    .map(|guild| guild.id.0)
+                    .collect();
+                mutations::catch_up_removals(
+                    &data.state,
+                    data.storage.as_ref(),
+                    &present,
+                    data_about_bot.shard,
+                )
+                .await;
+            }
+            Event::Resume { .. } => data.health.set_gateway_connected(true),
+            Event::ShardStageUpdate { update } => {
+                let connected = update.new == ConnectionStage::Connected;
+                if !connected {
+                    warn!(stage = %update.new, "Gateway connection lost");
+                }
+                data.health.set_gateway_connected(connected);
+            }
+            Event::GuildCreate { guild, .. } => {
+                mutations::guild_available(&data.state, data.storage.as_ref(), guild.id.0).await
+            }
+            // An unavailable guild is an outage on Discord's side, the bot is still in it
+            Event::GuildDelete { incomplete, .. } if !incomplete.unavailable => {
+                mutations::guild_removed(&data.state, data.storage.as_ref(), incomplete.id.0).await
+            }
+            Event::GuildMemberRemoval { guild_id, user, .. } => {
+                mutations::member_left(&data.state, data.storage.as_ref(), guild_id.0, user.id.0)
+                    .await
+            }
+            Event::GuildMemberAddition { new_member } => {
+                mutations::member_joined(
+                    &data.state,
+                    data.storage.as_ref(),
+                    new_member.guild_id.0,
+                    new_member.user.id.0,
+                )
+                .await
+            }
+            Event::ChannelDelete { channel } => {
+                channels::channel_deleted(
+                    &ctx.http,
+                    &data.state,
+                    data.storage.as_ref(),
+                    channel.guild_id.0,
+                    channel.id.0,
+                )
+                .await
+            }
+            _ => {}
+        }
+        Ok(())
+    })
+}
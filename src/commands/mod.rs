@@ -1,8 +1,12 @@
 mod bday;
+mod config;
+pub mod hooks;
+mod paginate;
 mod set_channel;
 mod timezone;
 
 use bday::*;
+use config::*;
 use poise::Command;
 use set_channel::*;
 use timezone::*;
@@ -10,5 +14,5 @@ use timezone::*;
 use crate::structs::{Data, Error};
 
 pub fn get_commands() -> Vec<Command<Data, Error>> {
-    vec![bday(), timezone(), channel()]
+    vec![bday(), timezone(), channel(), config()]
 }
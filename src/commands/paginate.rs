@@ -0,0 +1,107 @@
+use std::{sync::Arc, time::Duration};
+
+use poise::serenity_prelude::{self as serenity, CollectComponentInteraction};
+
+use crate::structs::{Context, Error};
+
+/// Discord rejects messages over 2000 characters, leave room for the page indicator
+const MAX_PAGE_LEN: usize = 1800;
+const MAX_PAGE_LINES: usize = 20;
+const NAVIGATION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Splits lines into pages that each fit in a single message, every page starting with the header
+pub fn build_pages(header: &str, lines: &[String]) -> Vec<String> {
+    let mut pages = vec![];
+    let mut current = header.to_string();
+    let mut line_count = 0;
+
+    for line in lines {
+        if line_count > 0
+            && (line_count >= MAX_PAGE_LINES || current.len() + line.len() + 1 > MAX_PAGE_LEN)
+        {
+            pages.push(current);
+            current = header.to_string();
+            line_count = 0;
+        }
+        current += line;
+        current.push('\n');
+        line_count += 1;
+    }
+    pages.push(current);
+    pages
+}
+
+fn render_page(pages: &[String], index: usize) -> String {
+    format!("{}\nPage {}/{}", pages[index], index + 1, pages.len())
+}
+
+/// Sends the pages with previous/next buttons that only the invoking user can press
+pub async fn paginate(ctx: Context<'_>, pages: &[String]) -> Result<(), Error> {
+    if pages.len() <= 1 {
+        ctx.say(pages.first().cloned().unwrap_or_default()).await?;
+        return Ok(());
+    }
+
+    let ctx_id = ctx.id();
+    let prev_button_id = format!("{}prev", ctx_id);
+    let next_button_id = format!("{}next", ctx_id);
+    let author_id = ctx.author().id;
+
+    let mut current_page = 0;
+    let reply = ctx
+        .send(|b| {
+            b.content(render_page(pages, current_page)).components(|b| {
+                b.create_action_row(|b| {
+                    b.create_button(|b| b.custom_id(&prev_button_id).emoji('◀'))
+                        .create_button(|b| b.custom_id(&next_button_id).emoji('▶'))
+                })
+            })
+        })
+        .await?;
+
+    let button_ids = Arc::new([prev_button_id.clone(), next_button_id.clone()]);
+    while let Some(press) = CollectComponentInteraction::new(ctx)
+        .filter({
+            let button_ids = Arc::clone(&button_ids);
+            move |press| button_ids.contains(&press.data.custom_id)
+        })
+        .timeout(NAVIGATION_TIMEOUT)
+        .await
+    {
+        if press.user.id != author_id {
+            press
+                .create_interaction_response(ctx, |b| {
+                    b.kind(serenity::InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|b| {
+                            b.content("Only the person who ran the command can change pages")
+                                .ephemeral(true)
+                        })
+                })
+                .await?;
+            continue;
+        }
+
+        if press.data.custom_id == next_button_id {
+            current_page = (current_page + 1) % pages.len();
+        } else {
+            current_page = current_page.checked_sub(1).unwrap_or(pages.len() - 1);
+        }
+
+        press
+            .create_interaction_response(ctx, |b| {
+                b.kind(serenity::InteractionResponseType::UpdateMessage)
+                    .interaction_response_data(|b| b.content(render_page(pages, current_page)))
+            })
+            .await?;
+    }
+
+    // Navigation has timed out, drop the buttons so nobody presses dead ones
+    reply
+        .edit(ctx, |b| {
+            b.content(render_page(pages, current_page))
+                .components(|b| b)
+        })
+        .await?;
+
+    Ok(())
+}
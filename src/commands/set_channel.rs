@@ -1,14 +1,18 @@
+use crate::channels::validate_announcement_channel;
+use crate::mutations::set_announcement_channel;
 use crate::structs::{Context, Error};
 use poise::serenity_prelude::Channel;
 
 /// Set the channel where messages will appear (MUST BE RUN)
-#[poise::command(slash_command)]
+#[poise::command(
+    slash_command,
+    required_permissions = "MANAGE_GUILD",
+    default_member_permissions = "MANAGE_GUILD"
+)]
 pub async fn channel(
     ctx: Context<'_>,
     #[description = "The channel that messages will be sent in"] channel: Channel,
 ) -> Result<(), Error> {
-    let data = &ctx.data().state;
-
     let guild_id = match ctx.guild_id() {
         Some(id) => id.0,
         None => {
@@ -18,16 +22,44 @@ pub async fn channel(
         }
     };
 
-    let mut guild_data_mut = data.guild_map.write().await;
-    let guild_entry = guild_data_mut.entry(guild_id).or_default();
-
-    let mut guild_entry_write = guild_entry.rw_lock.write().await;
-
-    guild_entry_write.announcement_channel = Some(channel.id().0);
-
-    ctx.data().saver.save();
+    ctx.defer().await?;
+    let warnings = match validate_announcement_channel(
+        &ctx.serenity_context().http,
+        guild_id,
+        ctx.framework().bot_id,
+        channel.id(),
+    )
+    .await
+    {
+        Ok(warnings) => warnings,
+        Err(problem) => {
+            ctx.say(format!("Channel not set: {}", problem)).await?;
+            return Ok(());
+        }
+    };
 
-    ctx.say("Channel successfully set!").await?;
+    match set_announcement_channel(
+        &ctx.data().state,
+        ctx.data().storage.as_ref(),
+        guild_id,
+        Some(channel.id().0),
+    )
+    .await
+    {
+        Ok(_) if warnings.is_empty() => ctx.say("Channel successfully set!").await?,
+        Ok(_) => {
+            ctx.say(format!(
+                "Channel successfully set, but:\n{}",
+                warnings
+                    .iter()
+                    .map(|warning| format!("- {}", warning))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ))
+            .await?
+        }
+        Err(e) => ctx.say(e.to_string()).await?,
+    };
 
     Ok(())
 }
@@ -1,6 +1,5 @@
+use crate::mutations::set_timezone;
 use crate::structs::{Context, Error};
-use chrono_tz::Tz;
-use std::str::FromStr;
 
 /// Sets the server default timezone
 #[poise::command(slash_command)]
@@ -8,8 +7,6 @@ pub async fn timezone(
     ctx: Context<'_>,
     #[description = "The timezone to set the server default to"] timezone_str: String,
 ) -> Result<(), Error> {
-    let data = &ctx.data().state;
-
     let guild_id = match ctx.guild_id() {
         Some(id) => id.0,
         None => {
@@ -19,26 +16,17 @@ pub async fn timezone(
         }
     };
 
-    let mut guild_data_mut = data.guild_map.write().await;
-    let guild_entry = guild_data_mut.entry(guild_id).or_default();
-
-    // Parse Timezone
-    let timezone: Result<Tz, _> = Tz::from_str(&timezone_str);
-
-    let timezone_to_set: Tz = match timezone {
-        Ok(tz) => tz,
-        Err(e) => {
-            ctx.say(format!("Invalid Timezone: due to {}", e)).await?;
-            return Ok(());
-        }
+    match set_timezone(
+        &ctx.data().state,
+        ctx.data().storage.as_ref(),
+        guild_id,
+        Some(&timezone_str),
+    )
+    .await
+    {
+        Ok(_) => ctx.say("Default timezone set successfully!").await?,
+        Err(e) => ctx.say(e.to_string()).await?,
     };
 
-    let mut guild_entry_mut = guild_entry.rw_lock.write().await;
-    guild_entry_mut.timezone = Some(timezone_to_set);
-
-    ctx.data().saver.save();
-
-    ctx.say("Default timezone set successfully!").await?;
-
     Ok(())
 }
@@ -0,0 +1,557 @@
+use std::{
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::{logging::LogFormat, storage::Backend};
+
+/// Looked for in the working directory when no config file is given
+const DEFAULT_CONFIG_FILE: &str = "origin-bot.toml";
+
+/// Placeholder in the announcement template that is swapped for who is celebrating
+pub const USER_PLACEHOLDER: &str = "{user}";
+
+// Everything given on the command line, each flag can also come from the environment.
+// Anything left out falls back to the config file and then to the defaults.
+#[derive(Parser)]
+#[command(author,version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Config file to read, defaults to origin-bot.toml when it exists
+    #[arg(short, long, env = "ORIGIN_BOT_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// File holding the Discord token, used when DISCORD_TOKEN is not set
+    #[arg(long, env = "DISCORD_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+    /// Follow members leaving and rejoining, needs the Server Members intent
+    #[arg(long, env = "TRACK_MEMBERS")]
+    pub track_members: Option<bool>,
+    /// Location to save and load from
+    #[arg(short, long, env = "SAVE_LOCATION")]
+    pub save_location: Option<PathBuf>,
+    /// Which kind of storage to keep the state in
+    #[arg(long, env = "STORAGE_BACKEND", value_enum)]
+    pub storage_backend: Option<Backend>,
+    /// Database file used by the sqlite storage
+    #[arg(long, env = "SQLITE_PATH")]
+    pub sqlite_path: Option<PathBuf>,
+    /// Directory used by the sharded storage
+    #[arg(long, env = "SAVE_DIR")]
+    pub save_dir: Option<PathBuf>,
+    /// Replace the save with a backup before starting, by file name or "latest"
+    #[arg(long)]
+    pub restore_backup: Option<String>,
+    /// When the save cannot be read, move it aside and start with whatever guilds can be
+    /// salvaged from it instead of refusing to start. Only for the json backend.
+    #[arg(long)]
+    pub recover: bool,
+    /// Seconds between checks for birthdays that have come around
+    #[arg(long, env = "TICK_INTERVAL_SECS")]
+    pub tick_interval_secs: Option<u64>,
+    /// Seconds each shutdown step gets before it is given up on
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+    /// Port to serve the calendar and RSS feeds on, feeds are off without it
+    #[arg(long, env = "HTTP_PORT")]
+    pub http_port: Option<u16>,
+    /// Address the feeds are reachable at from outside, used when handing out feed links
+    #[arg(long, env = "PUBLIC_URL")]
+    pub public_url: Option<String>,
+    /// Port to serve metrics and, with ADMIN_TOKEN set, the admin API on (localhost only)
+    #[arg(long, env = "ADMIN_PORT")]
+    pub admin_port: Option<u16>,
+    /// Log filter, such as "debug" or "origin_bot=trace,warn" (overrides RUST_LOG)
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<String>,
+    /// How log lines are written
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Copy the JSON save into the SQLite database, then exit
+    MigrateToSqlite {
+        /// Database to write to, defaults to the configured sqlite path
+        #[arg(long)]
+        to: Option<PathBuf>,
+        /// Replace any guilds already in the database
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Copy the JSON save into a file per guild, then exit
+    MigrateToSharded {
+        /// Directory to write to, defaults to the configured save directory
+        #[arg(long)]
+        to: Option<PathBuf>,
+        /// Replace any guilds already in the directory
+        #[arg(long)]
+        overwrite: bool,
+    },
+}
+
+/// The resolved configuration, defaults overlaid by the config file, the environment and the CLI
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub discord: DiscordConfig,
+    pub storage: StorageConfig,
+    pub scheduler: SchedulerConfig,
+    pub shutdown: ShutdownConfig,
+    pub logging: LoggingConfig,
+    pub http: HttpConfig,
+    pub templates: Templates,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordConfig {
+    /// Read when DISCORD_TOKEN is not set, keeps the token out of the environment
+    pub token_file: Option<PathBuf>,
+    /// Whether to follow members leaving and rejoining for the departure policy. The intent it
+    /// needs is privileged, so it is off unless asked for.
+    pub track_members: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    /// The JSON save, also where migrate-to-sqlite and migrate-to-sharded read from
+    pub save_location: PathBuf,
+    pub sqlite_path: PathBuf,
+    /// Holds a file per guild for the sharded storage
+    pub save_dir: PathBuf,
+    /// Mutation log used by the wal storage, defaults to the save's name with .wal added
+    pub wal_path: Option<PathBuf>,
+    /// How many logged changes the wal storage takes before folding them into the snapshot
+    pub wal_compact_after: usize,
+    /// The longest the wal storage goes between snapshots, however few changes there were
+    pub wal_compact_interval_secs: u64,
+    /// Where backups go, defaults to a folder next to the save named after it
+    pub backup_dir: Option<PathBuf>,
+    /// How many backups to keep, 0 turns them off
+    pub backups_kept: usize,
+    /// The least time between two backups, so a burst of saves does not push out all the history
+    pub backup_interval_secs: u64,
+    /// Only ever set from the command line, as it is meant for a single start
+    #[serde(skip)]
+    pub recover: bool,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Json,
+            save_location: PathBuf::from("birthdays.json"),
+            sqlite_path: PathBuf::from("birthdays.sqlite3"),
+            save_dir: PathBuf::from("guilds"),
+            wal_path: None,
+            wal_compact_after: 1000,
+            wal_compact_interval_secs: 3600,
+            backup_dir: None,
+            backups_kept: 5,
+            backup_interval_secs: 3600,
+            recover: false,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn backup_dir(&self) -> PathBuf {
+        match &self.backup_dir {
+            Some(dir) => dir.clone(),
+            None => self.next_to_save(".backups"),
+        }
+    }
+
+    /// Unreadable saves are moved in here along with a report of what was recovered
+    pub fn quarantine_dir(&self) -> PathBuf {
+        self.next_to_save(".quarantine")
+    }
+
+    pub fn wal_path(&self) -> PathBuf {
+        match &self.wal_path {
+            Some(path) => path.clone(),
+            None => self.next_to_save(".wal"),
+        }
+    }
+
+    pub fn wal_compact_interval(&self) -> Duration {
+        Duration::from_secs(self.wal_compact_interval_secs)
+    }
+
+    /// The save's own name with something added on
+    fn next_to_save(&self, suffix: &str) -> PathBuf {
+        let mut name = self
+            .save_location
+            .file_name()
+            .unwrap_or_default()
+            .to_os_string();
+        name.push(suffix);
+        self.save_location.with_file_name(name)
+    }
+
+    pub fn backup_interval(&self) -> Duration {
+        Duration::from_secs(self.backup_interval_secs)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    pub tick_interval_secs: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            tick_interval_secs: 900,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_secs(self.tick_interval_secs)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    pub timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { timeout_secs: 30 }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Falls back to RUST_LOG and then the built in filter when unset
+    pub level: Option<String>,
+    pub format: LogFormat,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub feed_port: Option<u16>,
+    pub public_url: Option<String>,
+    pub admin_port: Option<u16>,
+}
+
+/// Messages the bot sends that servers commonly want worded differently
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Templates {
+    /// Sent on someone's birthday, {user} becomes their mention or name
+    pub announcement: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            announcement: format!("Happy Birthday {} :tada::tada::tada:", USER_PLACEHOLDER),
+        }
+    }
+}
+
+impl Templates {
+    pub fn announcement(&self, celebrated: &str) -> String {
+        self.announcement.replace(USER_PLACEHOLDER, celebrated)
+    }
+}
+
+/// Everything wrong with a configuration, gathered up so it can all be fixed in one go
+#[derive(Debug)]
+pub struct ConfigError {
+    pub source: Option<PathBuf>,
+    pub problems: Vec<String>,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(path) => write!(f, "Invalid configuration (from {}):", path.display())?,
+            None => write!(f, "Invalid configuration:")?,
+        }
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Builds the configuration from every layer and checks it before anything starts
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let (mut config, source) = Self::from_file(args.config.as_deref())?;
+        config.apply_args(args);
+
+        let problems = config.problems();
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { source, problems })
+        }
+    }
+
+    fn from_file(path: Option<&Path>) -> Result<(Self, Option<PathBuf>), ConfigError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+                if !default.exists() {
+                    return Ok((Self::default(), None));
+                }
+                default
+            }
+        };
+
+        let fail = |problem: String| ConfigError {
+            source: Some(path.clone()),
+            problems: vec![problem],
+        };
+
+        let text = fs::read_to_string(&path).map_err(|e| fail(format!("Could not read: {}", e)))?;
+        let config = toml::from_str(&text).map_err(|e| fail(e.to_string()))?;
+        Ok((config, Some(path)))
+    }
+
+    /// The environment and CLI share a layer, clap has already preferred the CLI where both are set
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(token_file) = &args.token_file {
+            self.discord.token_file = Some(token_file.clone());
+        }
+        if let Some(track_members) = args.track_members {
+            self.discord.track_members = track_members;
+        }
+        if let Some(backend) = args.storage_backend {
+            self.storage.backend = backend;
+        }
+        if let Some(sqlite_path) = &args.sqlite_path {
+            self.storage.sqlite_path = sqlite_path.clone();
+        }
+        if let Some(save_dir) = &args.save_dir {
+            self.storage.save_dir = save_dir.clone();
+        }
+        if let Some(save_location) = &args.save_location {
+            self.storage.save_location = save_location.clone();
+        }
+        self.storage.recover = args.recover;
+        if let Some(secs) = args.tick_interval_secs {
+            self.scheduler.tick_interval_secs = secs;
+        }
+        if let Some(secs) = args.shutdown_timeout_secs {
+            self.shutdown.timeout_secs = secs;
+        }
+        if let Some(level) = &args.log_level {
+            self.logging.level = Some(level.clone());
+        }
+        if let Some(format) = args.log_format {
+            self.logging.format = format;
+        }
+        if let Some(port) = args.http_port {
+            self.http.feed_port = Some(port);
+        }
+        if let Some(url) = &args.public_url {
+            self.http.public_url = Some(url.clone());
+        }
+        if let Some(port) = args.admin_port {
+            self.http.admin_port = Some(port);
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.storage.save_location.as_os_str().is_empty() {
+            problems.push("storage.save_location cannot be empty".to_string());
+        }
+        if self.storage.backend == Backend::Sqlite
+            && self.storage.sqlite_path.as_os_str().is_empty()
+        {
+            problems.push("storage.sqlite_path cannot be empty".to_string());
+        }
+        if self.storage.backend == Backend::Sharded && self.storage.save_dir.as_os_str().is_empty()
+        {
+            problems.push("storage.save_dir cannot be empty".to_string());
+        }
+        if self.storage.backend == Backend::Wal && self.storage.recover {
+            problems.push(
+                "--recover cannot be used with the wal backend, the mutation log would be thrown away. Restore a backup with --restore-backup instead".to_string(),
+            );
+        }
+        if self.storage.backend == Backend::Wal && self.storage.wal_compact_after == 0 {
+            problems.push("storage.wal_compact_after must be at least 1".to_string());
+        }
+        if self.storage.backup_dir.is_none() && self.storage.save_location.file_name().is_none() {
+            problems.push("storage.save_location must name a file".to_string());
+        }
+        if let Some(token_file) = &self.discord.token_file {
+            if !token_file.is_file() {
+                problems.push(format!(
+                    "discord.token_file {} does not exist",
+                    token_file.display()
+                ));
+            }
+        }
+        if !(60..=86_400).contains(&self.scheduler.tick_interval_secs) {
+            problems.push(format!(
+                "scheduler.tick_interval_secs must be between 60 and 86400, got {}",
+                self.scheduler.tick_interval_secs
+            ));
+        }
+        if !(1..=600).contains(&self.shutdown.timeout_secs) {
+            problems.push(format!(
+                "shutdown.timeout_secs must be between 1 and 600, got {}",
+                self.shutdown.timeout_secs
+            ));
+        }
+        if let Some(level) = &self.logging.level {
+            if let Err(e) = EnvFilter::try_new(level) {
+                problems.push(format!("logging.level \"{}\" is not valid: {}", level, e));
+            }
+        }
+        if self.http.feed_port.is_some() && self.http.feed_port == self.http.admin_port {
+            problems.push("http.feed_port and http.admin_port cannot be the same".to_string());
+        }
+        if let Some(url) = &self.http.public_url {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                problems.push(format!(
+                    "http.public_url must start with http:// or https://, got \"{}\"",
+                    url
+                ));
+            }
+            if self.http.feed_port.is_none() {
+                problems.push("http.public_url is set but http.feed_port is not".to_string());
+            }
+        }
+        if !self.templates.announcement.contains(USER_PLACEHOLDER) {
+            problems.push(format!(
+                "templates.announcement must contain {}",
+                USER_PLACEHOLDER
+            ));
+        }
+
+        problems
+    }
+
+    /// DISCORD_TOKEN wins over the token file, as the environment sits above the file
+    pub fn discord_token(&self, from_env: Option<String>) -> anyhow::Result<String> {
+        if let Some(token) = from_env {
+            return Ok(token);
+        }
+        match &self.discord.token_file {
+            Some(path) => {
+                let token = fs::read_to_string(path).map_err(|e| {
+                    anyhow::anyhow!("Could not read token file {}: {}", path.display(), e)
+                })?;
+                Ok(token.trim().to_string())
+            }
+            None => Err(anyhow::anyhow!(
+                "No Discord token, set DISCORD_TOKEN or discord.token_file"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "origin-bot-config-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn args(path: &Path, flags: &[&str]) -> Args {
+        let mut argv = vec!["origin-bot", "--config", path.to_str().unwrap()];
+        argv.extend_from_slice(flags);
+        Args::try_parse_from(argv).unwrap()
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_file_which_overrides_the_defaults() {
+        let path = write_config(
+            "layers",
+            "[scheduler]\ntick_interval_secs = 120\n\n[storage]\nsave_location = \"from-file.json\"\n",
+        );
+        let config = Config::load(&args(&path, &["--save-location", "from-cli.json"])).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.scheduler.tick_interval_secs, 120);
+        assert_eq!(config.storage.save_location, PathBuf::from("from-cli.json"));
+        assert_eq!(config.shutdown.timeout_secs, 30);
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let path = write_config(
+            "problems",
+            "[scheduler]\ntick_interval_secs = 5\n\n[templates]\nannouncement = \"Happy birthday\"\n",
+        );
+        let err = Config::load(&args(&path, &["--shutdown-timeout-secs", "0"])).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(err.source.as_deref(), Some(path.as_path()));
+        assert_eq!(err.problems.len(), 3, "{}", err);
+        assert!(err.to_string().contains("scheduler.tick_interval_secs"));
+        assert!(err.to_string().contains("shutdown.timeout_secs"));
+        assert!(err.to_string().contains("templates.announcement"));
+    }
+
+    #[test]
+    fn unknown_keys_in_the_file_are_refused() {
+        let path = write_config("unknown", "[scheduler]\ntick_interval = 120\n");
+        let err = Config::load(&args(&path, &[])).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(err.problems.len(), 1);
+        assert!(err.problems[0].contains("tick_interval"), "{}", err);
+    }
+
+    #[test]
+    fn the_token_from_the_environment_wins_over_the_token_file() {
+        let token_file = write_config("token", "from-file\n");
+        let config = Config {
+            discord: DiscordConfig {
+                token_file: Some(token_file.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(config.discord_token(None).unwrap(), "from-file");
+        assert_eq!(
+            config.discord_token(Some("from-env".to_string())).unwrap(),
+            "from-env"
+        );
+        fs::remove_file(&token_file).unwrap();
+    }
+}
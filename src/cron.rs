@@ -1,53 +1,168 @@
 use std::{sync::Arc, time::Duration};
 
-use chronoutil::delta;
+use chrono::Utc;
 use poise::serenity_prelude::{ChannelId, Mention, UserId};
 use serenity::CacheAndHttp;
+use tracing::{debug, info, info_span, warn, Instrument};
 
-use crate::structs::{BirthdayInfo, Data};
+use crate::{
+    channels::{clear_misconfigured, flag_misconfigured, ChannelProblem},
+    structs::{BirthdayInfo, Data, RWGuildData},
+};
 
-pub async fn bday_crunching(context: Arc<CacheAndHttp>, data: Data) {
-    let mut interval_timer = tokio::time::interval(Duration::from_secs(900));
+pub async fn bday_crunching(
+    context: Arc<CacheAndHttp>,
+    data: Data,
+    tick_interval: Duration,
+) -> anyhow::Result<()> {
+    let mut interval_timer = tokio::time::interval(tick_interval);
     loop {
-        interval_timer.tick().await;
-
-        let global_reader = data.state.guild_map.read().await;
-        println!("Global Reader obtained");
-        for (guild_id, guild_data) in global_reader.iter() {
-            let happened_bdays = {
-                let mut writer = guild_data.rw_lock.write().await;
-                writer.birthday_schedule.pop_occured()
-            };
-            let announcement_channel = { guild_data.rw_lock.read().await.announcement_channel };
-            for bday in happened_bdays {
-                let user_id = bday.associated_user;
-                let channel_id = announcement_channel.unwrap_or_default();
-                let channel = ChannelId(channel_id);
-                let user = UserId(user_id);
-
-                if channel
-                    .say(
-                        &context.http,
-                        format!("Happy Birthday {} :tada::tada::tada:", Mention::User(user)),
-                    )
-                    .await
-                    .is_err()
-                {
-                    println!(
-                        "Could not wish happy birthday to {} on channel {} on server {}",
-                        user_id, channel_id, guild_id
-                    )
-                };
-
-                let new_insert = Arc::new(BirthdayInfo {
-                    datetime: delta::shift_years(bday.datetime, 1),
-                    associated_user: user_id,
-                });
-
-                let mut writer = guild_data.rw_lock.write().await;
-                let _ = writer.birthday_schedule.insert(new_insert);
+        tokio::select! {
+            _ = interval_timer.tick() => {}
+            _ = data.shutdown.wait() => return Ok(()),
+        }
+
+        // Announcing waits on Discord, so the map is not held while it happens
+        let guilds: Vec<(u64, Arc<RWGuildData>)> = data
+            .state
+            .guild_map
+            .read()
+            .await
+            .iter()
+            .map(|(guild_id, guild_data)| (*guild_id, Arc::clone(guild_data)))
+            .collect();
+        debug!(guilds = guilds.len(), "Checking for birthdays");
+        for (guild_id, guild_data) in guilds {
+            // Guilds are never cut off halfway, shutdown waits for the current one to finish
+            if data.shutdown.is_triggered() {
+                info!("Stopping birthday check for shutdown");
+                return Ok(());
             }
-            data.saver.save();
+            crunch_guild(&context, &data, guild_id, &guild_data)
+                .instrument(info_span!("guild", guild_id))
+                .await;
         }
+        data.health.record_cron_tick();
+    }
+}
+
+async fn crunch_guild(
+    context: &CacheAndHttp,
+    data: &Data,
+    guild_id: u64,
+    guild_data: &RWGuildData,
+) {
+    let (happened_bdays, purged, removed, absent, guild_timezone) = {
+        let mut writer = guild_data.rw_lock.write().await;
+        let purged = writer.trash.purge_expired(Utc::now());
+        (
+            writer.birthday_schedule.pop_occured(),
+            purged,
+            writer.removed_at.is_some(),
+            writer.absent.clone(),
+            writer.timezone,
+        )
+    };
+    let changed = purged > 0 || !happened_bdays.is_empty();
+    if purged > 0 {
+        info!(purged, "Purged expired birthdays from the trash");
+    }
+    let mut target = Target {
+        channel: guild_data
+            .rw_lock
+            .read()
+            .await
+            .announcement_channel
+            .map(ChannelId),
+        fell_back: false,
+    };
+    for bday in happened_bdays {
+        let user_id = bday.associated_user;
+        let user = UserId(user_id);
+
+        let celebrated = match &bday.name {
+            Some(name) => name.clone(),
+            None => Mention::User(user).to_string(),
+        };
+
+        // Still moved on to next year, so nothing piles up for when they are back
+        let announced = if removed || absent.contains(&user_id) {
+            debug!(user_id, removed, "Skipped a birthday while away");
+            None
+        } else {
+            let content = data.templates.announcement(&celebrated);
+            Some(announce(context, data, guild_id, &mut target, &content).await)
+        };
+        match announced {
+            None => {}
+            Some(Err(e)) => {
+                data.metrics.announcements_failed.inc();
+                warn!(user_id, error = %e, "Could not wish happy birthday");
+            }
+            Some(Ok(channel)) => {
+                data.metrics.announcements_sent.inc();
+                let lag = Utc::now() - bday.datetime;
+                data.metrics
+                    .scheduler_lag
+                    .observe(lag.num_milliseconds() as f64 / 1000.0);
+                info!(user_id, channel_id = channel.0, "Wished happy birthday");
+            }
+        };
+
+        let new_insert = Arc::new(BirthdayInfo {
+            datetime: bday.following_year(guild_timezone),
+            ..(*bday).clone()
+        });
+
+        let mut writer = guild_data.rw_lock.write().await;
+        let _ = writer.birthday_schedule.insert(new_insert);
+    }
+    if changed {
+        data.storage.mark_dirty(guild_id);
+    }
+}
+
+/// Where a guild's birthdays go for the rest of the tick
+struct Target {
+    channel: Option<ChannelId>,
+    /// Set once the configured channel turned out unusable and the fallback was looked up
+    fell_back: bool,
+}
+
+/// Sends an announcement, flagging the guild and switching to its fallback channel when the
+/// announcement channel is missing or unusable, and clearing the flag once it works again
+async fn announce(
+    context: &CacheAndHttp,
+    data: &Data,
+    guild_id: u64,
+    target: &mut Target,
+    content: &str,
+) -> anyhow::Result<ChannelId> {
+    loop {
+        let problem = match target.channel {
+            Some(channel) => match channel.say(&context.http, content).await {
+                Ok(_) => {
+                    if !target.fell_back {
+                        clear_misconfigured(&data.state, data.storage.as_ref(), guild_id).await;
+                    }
+                    return Ok(channel);
+                }
+                Err(e) => match ChannelProblem::from_error(&e) {
+                    Some(problem) if !target.fell_back => problem,
+                    _ => return Err(e.into()),
+                },
+            },
+            None if !target.fell_back => ChannelProblem::NotSet,
+            None => anyhow::bail!("There is no channel to announce in"),
+        };
+        target.fell_back = true;
+        target.channel = flag_misconfigured(
+            &context.http,
+            &data.state,
+            data.storage.as_ref(),
+            guild_id,
+            problem,
+        )
+        .await;
     }
 }
@@ -0,0 +1,146 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// What a background task is doing, none of them are meant to ever finish
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TaskStatus {
+    Running,
+    Finished,
+    Failed { error: String },
+}
+
+/// Liveness of the bot's moving parts, shared by everything that reports into it
+pub struct Health {
+    gateway_connected: AtomicBool,
+    shutting_down: AtomicBool,
+    last_cron_tick: Mutex<Option<DateTime<Utc>>>,
+    last_save: Mutex<Option<DateTime<Utc>>>,
+    tasks: Mutex<BTreeMap<&'static str, TaskStatus>>,
+    /// How long the cron loop can go without ticking before the bot counts as not ready
+    cron_staleness: Duration,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub live: bool,
+    pub ready: bool,
+    pub gateway_connected: bool,
+    pub last_cron_tick: Option<DateTime<Utc>>,
+    pub last_save: Option<DateTime<Utc>>,
+    pub tasks: BTreeMap<&'static str, TaskStatus>,
+}
+
+impl Health {
+    /// The cron loop is allowed to miss one tick before it is considered stuck
+    pub fn new(cron_interval: Duration) -> Self {
+        Self {
+            gateway_connected: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+            last_cron_tick: Mutex::new(None),
+            last_save: Mutex::new(None),
+            tasks: Mutex::new(BTreeMap::new()),
+            cron_staleness: cron_interval * 2 + Duration::from_secs(60),
+        }
+    }
+
+    pub fn set_gateway_connected(&self, connected: bool) {
+        self.gateway_connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Takes the bot out of rotation, and stops tasks winding down from counting as failures
+    pub fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    pub fn record_cron_tick(&self) {
+        *self.last_cron_tick.lock().expect("Health lock poisoned") = Some(Utc::now());
+    }
+
+    pub fn record_save(&self) {
+        *self.last_save.lock().expect("Health lock poisoned") = Some(Utc::now());
+    }
+
+    fn set_task(&self, name: &'static str, status: TaskStatus) {
+        self.tasks
+            .lock()
+            .expect("Health lock poisoned")
+            .insert(name, status);
+    }
+
+    /// Spawns a long running task and keeps track of whether it is still going. The handle
+    /// resolves once the task has ended and its status is recorded.
+    pub fn spawn_task<F>(self: &Arc<Self>, name: &'static str, task: F) -> JoinHandle<()>
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.set_task(name, TaskStatus::Running);
+        let handle = tokio::spawn(task);
+        let health = Arc::clone(self);
+        tokio::spawn(async move {
+            let status = match handle.await {
+                Ok(Ok(())) if health.shutting_down.load(Ordering::Relaxed) => {
+                    info!(task = name, "Background task stopped for shutdown");
+                    TaskStatus::Finished
+                }
+                Ok(Ok(())) => {
+                    warn!(task = name, "Background task finished");
+                    TaskStatus::Finished
+                }
+                Ok(Err(e)) => {
+                    error!(task = name, error = %e, "Background task failed");
+                    TaskStatus::Failed {
+                        error: e.to_string(),
+                    }
+                }
+                Err(e) => {
+                    error!(task = name, error = %e, "Background task panicked");
+                    TaskStatus::Failed {
+                        error: e.to_string(),
+                    }
+                }
+            };
+            health.set_task(name, status);
+        })
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let tasks = self.tasks.lock().expect("Health lock poisoned").clone();
+        let last_cron_tick = *self.last_cron_tick.lock().expect("Health lock poisoned");
+        let last_save = *self.last_save.lock().expect("Health lock poisoned");
+        let gateway_connected = self.gateway_connected.load(Ordering::Relaxed);
+        let shutting_down = self.shutting_down.load(Ordering::Relaxed);
+
+        let live = tasks
+            .values()
+            .all(|status| matches!(status, TaskStatus::Running));
+        let cron_fresh = match last_cron_tick {
+            Some(tick) => (Utc::now() - tick)
+                .to_std()
+                .map(|since| since <= self.cron_staleness)
+                .unwrap_or(true),
+            None => false,
+        };
+
+        HealthReport {
+            live,
+            ready: live && gateway_connected && cron_fresh && !shutting_down,
+            gateway_connected,
+            last_cron_tick,
+            last_save,
+            tasks,
+        }
+    }
+}
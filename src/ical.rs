@@ -0,0 +1,301 @@
+use chrono::{DateTime, Datelike, Days, NaiveDate, Utc};
+use chrono_tz::Tz;
+
+use crate::{records::BirthdayRecord, structs::BirthdayInfo};
+
+/// Lines longer than this many octets have to be folded (RFC 5545 section 3.1)
+const MAX_LINE_OCTETS: usize = 75;
+
+/// A yearly all-day event
+pub struct CalendarEntry {
+    pub uid: String,
+    pub summary: String,
+    pub date: NaiveDate,
+}
+
+impl CalendarEntry {
+    /// None for private birthdays, which never go into a calendar
+    pub fn for_birthday(
+        guild_id: u64,
+        info: &BirthdayInfo,
+        guild_timezone: Option<Tz>,
+        name: &str,
+    ) -> Option<Self> {
+        if info.private {
+            return None;
+        }
+        Some(Self {
+            uid: format!("{}-{}@origin-bot", guild_id, info.associated_user),
+            summary: format!("{}'s birthday", name),
+            date: info.local_date(guild_timezone),
+        })
+    }
+}
+
+/// Renders the entries as an iCalendar document
+pub fn write_calendar(name: &str, entries: &[CalendarEntry], stamp: DateTime<Utc>) -> String {
+    let mut res = String::new();
+    let stamp = stamp.format("%Y%m%dT%H%M%SZ").to_string();
+
+    push_line(&mut res, "BEGIN:VCALENDAR");
+    push_line(&mut res, "VERSION:2.0");
+    push_line(&mut res, "PRODID:-//origin-bot//birthdays//EN");
+    push_line(&mut res, "CALSCALE:GREGORIAN");
+    push_line(&mut res, &format!("X-WR-CALNAME:{}", escape_text(name)));
+
+    for entry in entries {
+        let end = entry
+            .date
+            .checked_add_days(Days::new(1))
+            .unwrap_or(entry.date);
+        push_line(&mut res, "BEGIN:VEVENT");
+        push_line(&mut res, &format!("UID:{}", escape_text(&entry.uid)));
+        push_line(&mut res, &format!("DTSTAMP:{}", stamp));
+        push_line(
+            &mut res,
+            &format!("DTSTART;VALUE=DATE:{}", entry.date.format("%Y%m%d")),
+        );
+        push_line(
+            &mut res,
+            &format!("DTEND;VALUE=DATE:{}", end.format("%Y%m%d")),
+        );
+        // A plain yearly rule on the 29th of February only fires on leap years
+        if entry.date.month() == 2 && entry.date.day() == 29 {
+            push_line(&mut res, "RRULE:FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=-1");
+        } else {
+            push_line(&mut res, "RRULE:FREQ=YEARLY");
+        }
+        push_line(
+            &mut res,
+            &format!("SUMMARY:{}", escape_text(&entry.summary)),
+        );
+        push_line(&mut res, "TRANSP:TRANSPARENT");
+        push_line(&mut res, "END:VEVENT");
+    }
+
+    push_line(&mut res, "END:VCALENDAR");
+    res
+}
+
+fn escape_text(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            ';' => res.push_str("\\;"),
+            ',' => res.push_str("\\,"),
+            '\n' => res.push_str("\\n"),
+            '\r' => {}
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// Appends a content line, folding it without splitting multi-byte characters
+fn push_line(res: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        let len = c.len_utf8();
+        if octets + len > MAX_LINE_OCTETS {
+            res.push_str("\r\n ");
+            // The leading space of a continuation line counts towards its length
+            octets = 1;
+        }
+        res.push(c);
+        octets += len;
+    }
+    res.push_str("\r\n");
+}
+
+/// Joins folded lines back together, shared with vCard which folds the same way
+pub fn unfold_lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in text.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Splits a content line into its uppercased name, its parameters and its value
+pub fn split_property(line: &str) -> Option<(String, &str, &str)> {
+    let (head, value) = line.split_once(':')?;
+    let (name, params) = head.split_once(';').unwrap_or((head, ""));
+    Some((name.trim().to_uppercase(), params, value))
+}
+
+pub fn unescape_text(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => res.push('\n'),
+            Some(escaped) => res.push(escaped),
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+/// Reads the yearly events of a calendar as birthdays named after their summary
+pub fn read_calendar(text: &str) -> Vec<Result<BirthdayRecord, String>> {
+    let mut res = vec![];
+    let mut event: Option<(Option<String>, Option<String>, bool)> = None;
+
+    for line in unfold_lines(text) {
+        let (name, _, value) = match split_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+        match (name.as_str(), event.as_mut()) {
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VEVENT") => {
+                event = Some((None, None, false))
+            }
+            ("SUMMARY", Some(event)) => event.0 = Some(unescape_text(value)),
+            ("DTSTART", Some(event)) => event.1 = Some(value.trim().to_string()),
+            ("RRULE", Some(event)) => {
+                event.2 = value.to_uppercase().contains("FREQ=YEARLY");
+            }
+            ("END", Some(_)) if value.eq_ignore_ascii_case("VEVENT") => {
+                // Anything that does not repeat every year is not a birthday
+                if let Some((summary, start, true)) = event.take() {
+                    res.push(calendar_record(summary, start));
+                }
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+fn calendar_record(
+    summary: Option<String>,
+    start: Option<String>,
+) -> Result<BirthdayRecord, String> {
+    let summary = summary.ok_or("Yearly event without a summary")?;
+    let start = start.ok_or_else(|| format!("`{}` has no start date", summary))?;
+    // Only the date part matters, so both DATE and DATE-TIME values work
+    let date = NaiveDate::parse_from_str(start.get(..8).unwrap_or(&start), "%Y%m%d")
+        .map_err(|_| format!("`{}` has an unreadable start date `{}`", summary, start))?;
+
+    Ok(BirthdayRecord {
+        user: birthday_owner(&summary).to_string(),
+        date: date.format("%m/%d").to_string(),
+        timezone: None,
+        // The start of a yearly event is often just the first year it was entered, not a birth year
+        year: None,
+    })
+}
+
+/// Strips the usual "'s birthday" wording from an event summary to get the person's name
+fn birthday_owner(summary: &str) -> &str {
+    let trimmed = summary.trim();
+    let lower = trimmed.to_lowercase();
+    for suffix in ["'s birthday", "’s birthday", "s' birthday", " birthday"] {
+        if lower.ends_with(suffix) && lower.len() > suffix.len() {
+            // Lowercasing can change lengths for some scripts, so only cut when they line up
+            if let Some(owner) = trimmed.get(..trimmed.len() - suffix.len()) {
+                return owner.trim();
+            }
+        }
+    }
+    trimmed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(user_id: u64, summary: &str, date: &str) -> CalendarEntry {
+        CalendarEntry {
+            uid: format!("1-{}@origin-bot", user_id),
+            summary: summary.to_string(),
+            date: NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap(),
+        }
+    }
+
+    fn read_back(entries: &[CalendarEntry]) -> Vec<(String, String)> {
+        let text = write_calendar("Test, birthdays", entries, Utc::now());
+        read_calendar(&text)
+            .into_iter()
+            .map(|record| {
+                let record = record.unwrap();
+                (record.user, record.date)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn escaped_text_round_trips() {
+        let entries = [
+            entry(1, "Smith, Jane's birthday", "1990-05-04"),
+            entry(2, "A;B's birthday", "2000-12-31"),
+            entry(3, "First line\nsecond line\\end's birthday", "1985-01-01"),
+        ];
+        assert_eq!(
+            read_back(&entries),
+            [
+                ("Smith, Jane".to_string(), "05/04".to_string()),
+                ("A;B".to_string(), "12/31".to_string()),
+                (
+                    "First line\nsecond line\\end".to_string(),
+                    "01/01".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_folded_and_read_back_whole() {
+        let name = "Ünïcödé ".repeat(12);
+        let entries = [entry(1, &format!("{}'s birthday", name), "1999-07-14")];
+        let text = write_calendar("Test", &entries, Utc::now());
+
+        assert!(text.split("\r\n").all(|line| line.len() <= MAX_LINE_OCTETS));
+        assert!(text.contains("\r\n "));
+        assert_eq!(
+            read_back(&entries),
+            [(name.trim().to_string(), "07/14".to_string())]
+        );
+    }
+
+    #[test]
+    fn the_29th_of_february_repeats_on_the_last_day_of_the_month() {
+        let text = write_calendar(
+            "Test",
+            &[entry(1, "Leap's birthday", "2000-02-29")],
+            Utc::now(),
+        );
+        assert!(text.contains("RRULE:FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=-1\r\n"));
+    }
+
+    #[test]
+    fn private_birthdays_stay_out_of_calendars() {
+        let info: BirthdayInfo = serde_json::from_value(serde_json::json!({
+            "datetime": "2026-05-04T00:00:00Z",
+            "associated_user": 42,
+            "private": true,
+        }))
+        .unwrap();
+        assert!(CalendarEntry::for_birthday(1, &info, None, "Someone").is_none());
+
+        let public = BirthdayInfo {
+            private: false,
+            ..info
+        };
+        let entry = CalendarEntry::for_birthday(1, &public, None, "Someone").unwrap();
+        assert_eq!(entry.uid, "1-42@origin-bot");
+        assert_eq!(
+            read_back(&[entry]),
+            [("Someone".to_string(), "05/04".to_string())]
+        );
+    }
+}
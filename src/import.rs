@@ -0,0 +1,468 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use poise::{
+    futures_util::{stream, StreamExt},
+    serenity_prelude::GuildId,
+};
+use serenity::http::Http;
+
+use crate::{
+    names::{MemberName, NameResolver, MAX_CONCURRENT_FETCHES},
+    records::BirthdayRecord,
+    structs::{parse_day, validate_year, BirthdayInfo, GuildData},
+};
+
+/// Attachments bigger than this are refused before downloading
+pub const MAX_IMPORT_BYTES: u64 = 1024 * 1024;
+
+/// A row that passed validation, along with what to call it in the preview
+pub struct PlannedBirthday {
+    pub row: usize,
+    pub label: String,
+    pub info: Arc<BirthdayInfo>,
+}
+
+/// What an import would do, worked out without touching the schedule
+#[derive(Default)]
+pub struct ImportPlan {
+    pub new: Vec<PlannedBirthday>,
+    /// Rows for users that already have a different birthday registered
+    pub conflicts: Vec<PlannedBirthday>,
+    pub unchanged: usize,
+    pub invalid: Vec<(usize, String)>,
+}
+
+/// What applying a plan did to the schedule
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportOutcome {
+    pub applied: usize,
+    /// Rows left alone because they were set to something else since the preview
+    pub skipped: usize,
+    /// Rows not linked to a member that could not be added as every local ID is taken
+    pub no_free_id: usize,
+}
+
+impl ImportPlan {
+    /// How many of the new entries are not linked to a member
+    pub fn new_unlinked(&self) -> usize {
+        self.new
+            .iter()
+            .filter(|planned| !planned.info.is_linked())
+            .count()
+    }
+
+    /// Applies the plan to the guild, counting the rows written and the ones left alone
+    pub fn apply(&self, guild_data: &mut GuildData, overwrite_conflicts: bool) -> ImportOutcome {
+        let mut outcome = ImportOutcome::default();
+        let conflicts = if overwrite_conflicts {
+            self.conflicts.as_slice()
+        } else {
+            &[]
+        };
+        let planned_rows = self
+            .new
+            .iter()
+            .map(|planned| (planned, true))
+            .chain(conflicts.iter().map(|planned| (planned, false)));
+        for (planned, previewed_as_new) in planned_rows {
+            let mut info = Arc::clone(&planned.info);
+            // Checked again under the lock, a row previewed as new may clash with a birthday set
+            // while the preview was up, which nobody agreed to overwrite
+            if info.is_linked() && previewed_as_new {
+                if let Some(existing) = guild_data.birthday_schedule.get(info.associated_user) {
+                    if existing.datetime != info.datetime {
+                        outcome.skipped += 1;
+                        continue;
+                    }
+                }
+            }
+            if let Some(name) = &info.name {
+                // The schedule may have changed since the preview, so unlinked IDs are handed out now
+                let associated_user = match guild_data.birthday_schedule.find_unlinked(name) {
+                    Some(existing) => existing.associated_user,
+                    None => match guild_data.birthday_schedule.next_unlinked_id() {
+                        Some(id) => id,
+                        None => {
+                            outcome.no_free_id += 1;
+                            continue;
+                        }
+                    },
+                };
+                info = Arc::new(BirthdayInfo {
+                    associated_user,
+                    ..(*info).clone()
+                });
+            }
+            let _ = guild_data.birthday_schedule.insert(info);
+            outcome.applied += 1;
+        }
+        outcome
+    }
+}
+
+/// Who a row is for
+enum Target {
+    Member(u64, String),
+    /// A contact that does not match any member
+    Unlinked(String),
+}
+
+/// Turns a user column into a member ID, either directly or by looking the name up.
+/// Returns None if the name matches nobody.
+pub async fn resolve_user(http: &Http, guild_id: u64, user: &str) -> Result<Option<u64>, String> {
+    if let Ok(user_id) = user.parse::<u64>() {
+        return Ok(Some(user_id));
+    }
+
+    let candidates = GuildId(guild_id)
+        .search_members(http, user, Some(10))
+        .await
+        .map_err(|e| format!("Could not search for `{}`: {}", user, e))?;
+    let matches = candidates
+        .iter()
+        .filter(|member| {
+            member.display_name().eq_ignore_ascii_case(user)
+                || member.user.name.eq_ignore_ascii_case(user)
+        })
+        .collect::<Vec<_>>();
+
+    match matches.as_slice() {
+        [member] => Ok(Some(member.user.id.0)),
+        [] => Ok(None),
+        _ => Err(format!(
+            "`{}` matches {} members, use their user ID instead",
+            user,
+            matches.len()
+        )),
+    }
+}
+
+/// Rows whose user column has been matched against the members of the guild
+#[derive(Default)]
+pub struct ResolvedRows {
+    rows: Vec<(usize, Target, BirthdayRecord)>,
+    invalid: Vec<(usize, String)>,
+}
+
+/// Matches every row to a member, done before looking at the guild so no lock is held meanwhile.
+/// With `allow_unlinked` names that match nobody become entries of their own instead of errors.
+pub async fn resolve_rows(
+    http: &Http,
+    names: &NameResolver,
+    guild_id: u64,
+    rows: Vec<Result<BirthdayRecord, String>>,
+    allow_unlinked: bool,
+) -> ResolvedRows {
+    let mut res = ResolvedRows::default();
+    let mut matched = vec![];
+
+    let mut records = vec![];
+    for (index, row) in rows.into_iter().enumerate() {
+        match row {
+            Ok(record) => records.push((index + 1, record)),
+            Err(e) => res.invalid.push((index + 1, e)),
+        }
+    }
+    // Names are searched for a few at a time, as a big file would otherwise take a round trip
+    // per row one after the other
+    let lookups: Vec<_> = stream::iter(records)
+        .map(|(row_number, record)| async move {
+            let user = resolve_user(http, guild_id, &record.user).await;
+            (row_number, record, user)
+        })
+        .buffer_unordered(MAX_CONCURRENT_FETCHES)
+        .collect()
+        .await;
+
+    for (row_number, record, user) in lookups {
+        match user {
+            Ok(Some(user_id)) => matched.push((row_number, user_id, record)),
+            Ok(None) if allow_unlinked => {
+                let name = record.user.trim().to_string();
+                res.rows.push((row_number, Target::Unlinked(name), record));
+            }
+            Ok(None) => res
+                .invalid
+                .push((row_number, format!("No member named `{}`", record.user))),
+            Err(e) => res.invalid.push((row_number, e)),
+        }
+    }
+
+    let user_ids = matched
+        .iter()
+        .map(|(_, user_id, _)| *user_id)
+        .collect::<Vec<_>>();
+    let member_names = names.resolve(http, guild_id, &user_ids).await;
+
+    for (row_number, user_id, record) in matched {
+        match &member_names[&user_id] {
+            MemberName::Departed => res.invalid.push((
+                row_number,
+                format!("User {} is not a member of this server", user_id),
+            )),
+            name => res.rows.push((
+                row_number,
+                Target::Member(user_id, name.display().to_string()),
+                record,
+            )),
+        }
+    }
+    res.rows.sort_by_key(|(row, _, _)| *row);
+    res.invalid.sort_by_key(|(row, _)| *row);
+    res
+}
+
+/// Validates the rows against the guild and works out which ones are new, changed or invalid
+pub fn plan_import(guild_data: &GuildData, resolved: ResolvedRows) -> ImportPlan {
+    let mut plan = ImportPlan {
+        invalid: resolved.invalid,
+        ..Default::default()
+    };
+    let now = Utc::now();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for (row_number, target, record) in resolved.rows {
+        let key = match &target {
+            Target::Member(user_id, _) => user_id.to_string(),
+            Target::Unlinked(name) => name.to_lowercase(),
+        };
+        if let Some(first_row) = seen.get(&key) {
+            plan.invalid
+                .push((row_number, format!("Same person as row {}", first_row)));
+            continue;
+        }
+        seen.insert(key, row_number);
+
+        let tz = match &record.timezone {
+            Some(tz_str) if !tz_str.is_empty() => match Tz::from_str(tz_str) {
+                Ok(tz) => tz,
+                Err(e) => {
+                    plan.invalid
+                        .push((row_number, format!("Invalid timezone: {}", e)));
+                    continue;
+                }
+            },
+            _ => match guild_data.timezone {
+                Some(tz) => tz,
+                None => {
+                    plan.invalid.push((
+                        row_number,
+                        "No timezone given and the server has no default".to_string(),
+                    ));
+                    continue;
+                }
+            },
+        };
+
+        let (month, day) = match parse_day(&record.date) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                plan.invalid.push((row_number, e));
+                continue;
+            }
+        };
+
+        if let Some(Err(e)) = record.year.map(validate_year) {
+            plan.invalid.push((row_number, e));
+            continue;
+        }
+
+        let datetime = match BirthdayInfo::next_occurrence(month, day, tz, now) {
+            Some(datetime) => datetime,
+            None => {
+                plan.invalid.push((
+                    row_number,
+                    "Could not work out the next birthday in that timezone".to_string(),
+                ));
+                continue;
+            }
+        };
+
+        let (existing, label, associated_user, name) = match target {
+            Target::Member(user_id, label) => (
+                guild_data.birthday_schedule.get(user_id),
+                label,
+                user_id,
+                None,
+            ),
+            Target::Unlinked(name) => {
+                let existing = guild_data.birthday_schedule.find_unlinked(&name);
+                // New unlinked entries get their real ID when the plan is applied
+                let associated_user = existing.map(|info| info.associated_user).unwrap_or(0);
+                (
+                    existing,
+                    format!("{} (not a member)", name),
+                    associated_user,
+                    Some(name),
+                )
+            }
+        };
+
+        let planned = PlannedBirthday {
+            row: row_number,
+            label,
+            info: Arc::new(BirthdayInfo {
+                datetime,
+                associated_user,
+                timezone: Some(tz),
+                // Keep what the user chose for themselves when overwriting
+                private: existing.map(|info| info.private).unwrap_or(false),
+                year: record.year.or(existing.and_then(|info| info.year)),
+                name,
+                leap_day: (month, day) == (2, 29),
+            }),
+        };
+
+        match existing {
+            None => plan.new.push(planned),
+            Some(info)
+                if info.datetime == planned.info.datetime
+                    && info.timezone == planned.info.timezone
+                    && info.year == planned.info.year =>
+            {
+                plan.unchanged += 1
+            }
+            Some(_) => plan.conflicts.push(planned),
+        }
+    }
+
+    plan.invalid.sort_by_key(|(row, _)| *row);
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{read_csv, read_json, write_csv, write_json};
+
+    /// Stands in for resolve_rows, user IDs are taken as members and anything else as a name
+    fn resolved(rows: Vec<Result<BirthdayRecord, String>>) -> ResolvedRows {
+        let mut res = ResolvedRows::default();
+        for (index, row) in rows.into_iter().enumerate() {
+            let record = row.unwrap();
+            let target = match record.user.parse::<u64>() {
+                Ok(user_id) => Target::Member(user_id, record.user.clone()),
+                Err(_) => Target::Unlinked(record.user.clone()),
+            };
+            res.rows.push((index + 1, target, record));
+        }
+        res
+    }
+
+    fn entry(
+        guild: &GuildData,
+        user: Option<u64>,
+        (month, day): (u32, u32),
+        tz: Tz,
+        year: Option<i32>,
+    ) -> Arc<BirthdayInfo> {
+        let name = match user {
+            Some(_) => None,
+            None => Some("Grandma".to_string()),
+        };
+        Arc::new(BirthdayInfo {
+            datetime: BirthdayInfo::next_occurrence(month, day, tz, Utc::now()).unwrap(),
+            associated_user: user
+                .unwrap_or_else(|| guild.birthday_schedule.next_unlinked_id().unwrap()),
+            timezone: Some(tz),
+            private: false,
+            year,
+            name,
+            leap_day: (month, day) == (2, 29),
+        })
+    }
+
+    fn guild() -> GuildData {
+        let mut guild = GuildData {
+            timezone: Some(Tz::Europe__Berlin),
+            ..Default::default()
+        };
+        let entries = [
+            entry(
+                &guild,
+                Some(300000000000000000),
+                (7, 14),
+                Tz::America__New_York,
+                Some(1990),
+            ),
+            entry(
+                &guild,
+                Some(400000000000000000),
+                (2, 29),
+                Tz::Europe__Berlin,
+                None,
+            ),
+            entry(&guild, None, (12, 31), Tz::Asia__Tokyo, Some(1941)),
+        ];
+        for info in entries {
+            let _ = guild.birthday_schedule.insert(info);
+        }
+        guild
+    }
+
+    fn export(guild: &GuildData) -> Vec<BirthdayRecord> {
+        guild
+            .birthday_schedule
+            .ordered_iter()
+            .map(|info| BirthdayRecord::from_info(info, guild.timezone))
+            .collect()
+    }
+
+    fn assert_same_schedule(original: &GuildData, imported: &GuildData) {
+        // Privacy is not part of the export, everything else should survive it
+        let original = original
+            .birthday_schedule
+            .ordered_iter()
+            .collect::<Vec<_>>();
+        let imported = imported
+            .birthday_schedule
+            .ordered_iter()
+            .collect::<Vec<_>>();
+        assert_eq!(original.len(), imported.len());
+        for (a, b) in original.iter().zip(&imported) {
+            assert_eq!(a.associated_user, b.associated_user);
+            assert_eq!(a.datetime, b.datetime);
+            assert_eq!(a.timezone, b.timezone);
+            assert_eq!(a.year, b.year);
+            assert_eq!(a.name, b.name);
+            assert_eq!(a.leap_day, b.leap_day);
+        }
+    }
+
+    fn import_into_empty(rows: Vec<Result<BirthdayRecord, String>>) -> GuildData {
+        let mut imported = GuildData::default();
+        let plan = plan_import(&imported, resolved(rows));
+        assert!(plan.invalid.is_empty(), "{:?}", plan.invalid);
+        assert!(plan.conflicts.is_empty());
+        let outcome = plan.apply(&mut imported, false);
+        assert_eq!(
+            outcome,
+            ImportOutcome {
+                applied: 3,
+                ..Default::default()
+            }
+        );
+        imported
+    }
+
+    #[test]
+    fn a_csv_export_imports_back_into_the_same_schedule() {
+        let original = guild();
+        let csv = write_csv(&export(&original)).unwrap();
+        assert!(csv.contains("02/29"));
+
+        let imported = import_into_empty(read_csv(csv.as_bytes()));
+        assert_same_schedule(&original, &imported);
+    }
+
+    #[test]
+    fn a_json_export_imports_back_into_the_same_schedule() {
+        let original = guild();
+        let json = write_json(&export(&original)).unwrap();
+
+        let imported = import_into_empty(read_json(json.as_bytes()).unwrap());
+        assert_same_schedule(&original, &imported);
+    }
+}
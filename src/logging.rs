@@ -0,0 +1,37 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+/// Used when neither the CLI nor RUST_LOG say otherwise, keeps the libraries' chatter down
+const DEFAULT_FILTER: &str = "origin_bot=info,warn";
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+/// Installs the global subscriber. A level from the CLI wins over RUST_LOG.
+pub fn init(level: Option<&str>, format: LogFormat) -> anyhow::Result<()> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => {
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER))
+        }
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let res = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    res.map_err(|e| anyhow::anyhow!("Could not set up logging: {}", e))
+}
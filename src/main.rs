@@ -1,46 +1,103 @@
-use std::path::PathBuf;
-
 use clap::Parser;
+use config::{Args, Command, Config};
 use origin_bot::start_bot;
 use serde::Deserialize;
 use serenity::prelude::*;
+use tracing::{info, warn};
+use web::WebSettings;
 
+pub mod channels;
 pub mod commands;
+pub mod config;
 pub mod cron;
+pub mod health;
+pub mod ical;
+pub mod import;
+pub mod logging;
+pub mod metrics;
+pub mod migrations;
+pub mod mutations;
+pub mod names;
 mod origin_bot;
 pub mod persistence;
+pub mod records;
+pub mod recovery;
+pub mod shutdown;
+pub mod storage;
 pub mod structs;
+pub mod validation;
+pub mod vcard;
+pub mod web;
 
 #[derive(Deserialize)]
 struct DiscordBotEnv {
-    pub discord_token: String,
-}
-
-#[derive(Parser)]
-#[command(author,version, about, long_about = None)]
-struct Args {
-    /// Location to save and load from
-    #[arg(short, long)]
-    save_location: PathBuf,
+    /// Takes priority over the token file from the config
+    pub discord_token: Option<String>,
+    /// Bearer token the admin API expects
+    pub admin_token: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    dotenvy::dotenv()?;
+    // A .env file is optional, but one that exists and cannot be read is still an error
+    if let Err(e) = dotenvy::dotenv() {
+        if !e.not_found() {
+            return Err(e.into());
+        }
+    }
 
     let args = Args::parse();
 
-    let env_cofig: DiscordBotEnv = envy::from_env()?;
+    let config = Config::load(&args)?;
 
-    let token = env_cofig.discord_token;
+    logging::init(config.logging.level.as_deref(), config.logging.format)?;
 
-    let intents = GatewayIntents::empty();
+    if let Some(name) = &args.restore_backup {
+        let restored = persistence::restore_backup(&config.storage, name).await?;
+        info!(backup = %restored.display(), "Restored the save from a backup");
+    }
 
-    match start_bot(token, intents, args.save_location).await {
-        Ok(_) => {
-            println!("Starting Origin Bot...");
-            Ok(())
+    match args.command {
+        Some(Command::MigrateToSqlite { to, overwrite }) => {
+            let (copied, target) =
+                storage::sqlite::import_json(&config.storage, to, overwrite).await?;
+            info!(guilds = copied, database = %target.display(), "Copied the JSON save into SQLite");
+            return Ok(());
         }
-        Err(e) => Err(anyhow::anyhow!("Serenity Error: {}", e)),
+        Some(Command::MigrateToSharded { to, overwrite }) => {
+            let (copied, target) =
+                storage::sharded::import_json(&config.storage, to, overwrite).await?;
+            info!(guilds = copied, directory = %target.display(), "Copied the JSON save into a file per guild");
+            return Ok(());
+        }
+        None => {}
     }
+
+    let env_cofig: DiscordBotEnv = envy::from_env()?;
+
+    let token = config.discord_token(env_cofig.discord_token)?;
+
+    if config.http.admin_port.is_some() && env_cofig.admin_token.is_none() {
+        warn!(
+            "ADMIN_TOKEN is not set, the admin API is disabled, only /metrics, /health and /ready will be served"
+        );
+    }
+
+    let web_settings = WebSettings {
+        feed_port: config.http.feed_port,
+        public_url: config.http.public_url.clone(),
+        admin_port: config.http.admin_port,
+        admin_token: env_cofig.admin_token,
+    };
+
+    // GUILD_MEMBERS is privileged and has to be turned on for the bot in the developer portal
+    let intents = if config.discord.track_members {
+        GatewayIntents::GUILDS | GatewayIntents::GUILD_MEMBERS
+    } else {
+        GatewayIntents::GUILDS
+    };
+
+    info!("Starting Origin Bot...");
+
+    start_bot(token, intents, config, web_settings).await
 }
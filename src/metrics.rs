@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use tracing::error;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+/// Scheduler lag is measured against a 15 minute tick, so the buckets go up to a few ticks
+const LAG_BUCKETS: &[f64] = &[1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 86400.0];
+
+/// Everything exported on `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    pub commands: IntCounterVec,
+    pub command_latency: HistogramVec,
+    pub announcements_sent: IntCounter,
+    pub announcements_failed: IntCounter,
+    pub scheduler_lag: Histogram,
+    pub guilds: IntGauge,
+    pub birthdays: IntGauge,
+    pub save_duration: Histogram,
+    pub save_failures: IntCounter,
+    pub unsaved_guilds: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("origin_bot".to_string()), None)
+            .expect("Static metric registry prefix is valid");
+
+        let commands = IntCounterVec::new(
+            Opts::new(
+                "commands_total",
+                "Slash commands run, by command and outcome",
+            ),
+            &["command", "outcome"],
+        )
+        .expect("Static metric definition is valid");
+        let command_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "command_duration_seconds",
+                "Time taken to run a slash command",
+            ),
+            &["command"],
+        )
+        .expect("Static metric definition is valid");
+        let announcements_sent =
+            IntCounter::new("announcements_sent_total", "Birthday announcements posted")
+                .expect("Static metric definition is valid");
+        let announcements_failed = IntCounter::new(
+            "announcements_failed_total",
+            "Birthday announcements that could not be posted",
+        )
+        .expect("Static metric definition is valid");
+        let scheduler_lag = Histogram::with_opts(
+            HistogramOpts::new(
+                "scheduler_lag_seconds",
+                "Time between a birthday being due and its announcement being sent",
+            )
+            .buckets(LAG_BUCKETS.to_vec()),
+        )
+        .expect("Static metric definition is valid");
+        let guilds = IntGauge::new("guilds", "Guilds with stored data")
+            .expect("Static metric definition is valid");
+        let birthdays = IntGauge::new("birthdays", "Birthdays scheduled across all guilds")
+            .expect("Static metric definition is valid");
+        let save_duration = Histogram::with_opts(HistogramOpts::new(
+            "save_duration_seconds",
+            "Time taken to serialize and write the save file",
+        ))
+        .expect("Static metric definition is valid");
+        let save_failures = IntCounter::new("save_failures_total", "Saves that did not complete")
+            .expect("Static metric definition is valid");
+        let unsaved_guilds = IntGauge::new(
+            "unsaved_guilds",
+            "Guilds with changes still waiting to be written, after the last save attempt",
+        )
+        .expect("Static metric definition is valid");
+
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(commands.clone()),
+            Box::new(command_latency.clone()),
+            Box::new(announcements_sent.clone()),
+            Box::new(announcements_failed.clone()),
+            Box::new(scheduler_lag.clone()),
+            Box::new(guilds.clone()),
+            Box::new(birthdays.clone()),
+            Box::new(save_duration.clone()),
+            Box::new(save_failures.clone()),
+            Box::new(unsaved_guilds.clone()),
+        ];
+        for collector in collectors {
+            registry
+                .register(collector)
+                .expect("Metrics are only registered once");
+        }
+
+        Self {
+            registry,
+            commands,
+            command_latency,
+            announcements_sent,
+            announcements_failed,
+            scheduler_lag,
+            guilds,
+            birthdays,
+            save_duration,
+            save_failures,
+            unsaved_guilds,
+        }
+    }
+
+    pub fn record_command(&self, command: &str, outcome: &str, elapsed: Option<Duration>) {
+        self.commands.with_label_values(&[command, outcome]).inc();
+        if let Some(elapsed) = elapsed {
+            self.command_latency
+                .with_label_values(&[command])
+                .observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!(error = %e, "Could not encode metrics");
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
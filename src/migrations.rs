@@ -0,0 +1,234 @@
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::info;
+
+use crate::{
+    config::StorageConfig,
+    persistence::keep_copy,
+    structs::{ApplicationState, StateSnapshot},
+    validation::{read_guild_map, LoadReport},
+};
+
+/// Version written into every save. Bump it together with a new entry in MIGRATIONS whenever
+/// the shape of the save changes.
+pub const SAVE_VERSION: u64 = 1;
+
+const VERSION_KEY: &str = "version";
+
+/// Upgrades a save by one version, MIGRATIONS[n] takes a version n save to version n + 1
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+const MIGRATIONS: [Migration; SAVE_VERSION as usize] = [v0_to_v1];
+
+/// Saves from before versioning. Every field added since then has a serde default, so only the
+/// version needs adding, which happens once the chain is done.
+fn v0_to_v1(save: &mut Map<String, Value>) -> Result<(), String> {
+    if !save.contains_key("guild_map") {
+        return Err("Missing guild_map".to_string());
+    }
+    Ok(())
+}
+
+/// The state as written to disk, tagged with the version it was written as
+#[derive(Serialize)]
+pub struct VersionedSave<'a> {
+    version: u64,
+    /// Which mutation log goes with this snapshot, only set when the log storage wrote it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_generation: Option<u64>,
+    #[serde(flatten)]
+    state: &'a StateSnapshot,
+}
+
+impl<'a> VersionedSave<'a> {
+    pub fn new(state: &'a StateSnapshot) -> Self {
+        Self {
+            version: SAVE_VERSION,
+            log_generation: None,
+            state,
+        }
+    }
+
+    pub fn with_log_generation(mut self, generation: u64) -> Self {
+        self.log_generation = Some(generation);
+        self
+    }
+}
+
+/// Reads a save of any known version, backing the file up before upgrading it
+pub async fn load(text: &str, storage: &StorageConfig) -> anyhow::Result<ApplicationState> {
+    let mut save = match serde_json::from_str::<Value>(text)? {
+        Value::Object(save) => save,
+        _ => anyhow::bail!("The save is not a JSON object"),
+    };
+
+    let version = take_version(&mut save)?;
+    if version < SAVE_VERSION {
+        let backup = keep_copy(
+            storage,
+            &format!("v{}-before-migration", version),
+            text.as_bytes(),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not back up the save before migrating: {}", e))?;
+        info!(backup = %backup.display(), "Backed up the save before migrating");
+
+        migrate(&mut save, version, &storage.save_location)?;
+    }
+
+    let guilds = match save.remove("guild_map") {
+        Some(Value::Object(guilds)) => guilds,
+        Some(_) => anyhow::bail!("The guild_map in the save is not a JSON object"),
+        None => anyhow::bail!("There is no guild_map in the save"),
+    };
+    let mut report = LoadReport::default();
+    let guild_map = read_guild_map(guilds, &mut report);
+    report.log();
+    Ok(StateSnapshot { guild_map }.into())
+}
+
+/// Takes the version out of a save, refusing one written by a newer build
+pub fn take_version(save: &mut Map<String, Value>) -> anyhow::Result<u64> {
+    let version = match save.remove(VERSION_KEY) {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("The save version {} is not a number", version))?,
+    };
+    if version > SAVE_VERSION {
+        anyhow::bail!(
+            "The save is version {}, newer than the {} this build understands",
+            version,
+            SAVE_VERSION
+        );
+    }
+    Ok(version)
+}
+
+pub fn migrate(save: &mut Map<String, Value>, from: u64, location: &Path) -> anyhow::Result<()> {
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        migration(save).map_err(|e| {
+            anyhow::anyhow!(
+                "Could not migrate {} from version {}: {}",
+                location.display(),
+                version,
+                e
+            )
+        })?;
+        info!(from = version, to = version + 1, "Migrated the save");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::structs::GuildData;
+
+    const V0_SAVE: &str = include_str!("../tests/fixtures/save-v0.json");
+    const V1_SAVE: &str = include_str!("../tests/fixtures/save-v1.json");
+
+    const GUILD: u64 = 100;
+    const USER: u64 = 300000000000000000;
+
+    /// Storage settings with the save in a fresh folder of its own
+    fn storage_in(name: &str) -> (StorageConfig, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "origin-bot-migrations-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let storage = StorageConfig {
+            save_location: dir.join("birthdays.json"),
+            ..Default::default()
+        };
+        (storage, dir)
+    }
+
+    async fn guild(state: &ApplicationState) -> GuildData {
+        let guild_map = state.guild_map.read().await;
+        let guild = guild_map.get(&GUILD).expect("the guild was dropped");
+        let data = guild.rw_lock.read().await.clone();
+        data
+    }
+
+    fn files_in(dir: &Path) -> Vec<String> {
+        match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_v0_save_is_upgraded_and_backed_up_outside_the_rotation() {
+        let (storage, dir) = storage_in("v0");
+        let state = load(V0_SAVE, &storage).await.unwrap();
+
+        let guild = guild(&state).await;
+        assert_eq!(guild.timezone, Some(chrono_tz::Europe::Berlin));
+        assert_eq!(guild.announcement_channel, Some(200));
+        let bday = guild.birthday_schedule.get(USER).unwrap();
+        assert_eq!(bday.timezone, None);
+        assert!(!bday.private);
+        assert!(guild.trash.is_empty());
+        assert_eq!(guild.feed_token, None);
+
+        // Rotation only looks at the top of the backup folder
+        let backups = storage.backup_dir();
+        assert!(files_in(&backups)
+            .iter()
+            .all(|name| !name.ends_with(".json")));
+        let kept = files_in(&backups.join("kept"));
+        assert_eq!(kept.len(), 1);
+        assert!(kept[0].ends_with("-v0-before-migration.json"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn starting_again_on_the_same_v0_save_keeps_one_copy() {
+        let (storage, dir) = storage_in("v0-again");
+        load(V0_SAVE, &storage).await.unwrap();
+        load(V0_SAVE, &storage).await.unwrap();
+        assert_eq!(files_in(&storage.backup_dir().join("kept")).len(), 1);
+
+        let changed = V0_SAVE.replacen("Europe/Berlin", "Europe/Paris", 1);
+        load(&changed, &storage).await.unwrap();
+        assert_eq!(files_in(&storage.backup_dir().join("kept")).len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_current_save_is_read_as_is_without_a_backup() {
+        let (storage, dir) = storage_in("v1");
+        let state = load(V1_SAVE, &storage).await.unwrap();
+
+        let guild = guild(&state).await;
+        let bday = guild.birthday_schedule.get(USER).unwrap();
+        assert_eq!(bday.timezone, Some(chrono_tz::Europe::Berlin));
+        assert!(bday.private);
+        assert_eq!(bday.year, Some(1990));
+        assert_eq!(guild.trash.len(), 1);
+        assert_eq!(guild.feed_token.as_deref(), Some("secret"));
+        assert!(!storage.backup_dir().exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_save_from_a_newer_build_is_refused() {
+        let (storage, dir) = storage_in("newer");
+        let newer = V1_SAVE.replacen("\"version\": 1", "\"version\": 99", 1);
+        assert!(load(&newer, &storage).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,509 @@
+use std::{collections::HashSet, fmt, str::FromStr, sync::Arc};
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use tracing::{info, warn};
+
+use crate::{
+    import::{ImportOutcome, ImportPlan},
+    storage::Storage,
+    structs::{
+        parse_day, validate_year, ApplicationState, BirthdayInfo, DeparturePolicy, RWGuildData,
+        TrashedBirthday,
+    },
+    web::feeds::generate_token,
+};
+
+/// The changes that can be made to a guild, shared by the slash commands and the admin API
+/// so both validate and persist the same way
+#[derive(Debug)]
+pub enum MutationError {
+    InvalidTimezone(String),
+    NoDefaultTimezone,
+    InvalidDay(String),
+    InvalidYear(String),
+    NoNextOccurrence,
+    UnknownGuild,
+    NotRegistered,
+    NothingToRestore,
+    /// The birthday being restored was registered again since it was removed
+    AlreadyRegistered(TrashedBirthday),
+    /// The change is made in memory but the storage could not confirm it is on disk
+    NotSaved(String),
+}
+
+impl fmt::Display for MutationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MutationError::InvalidTimezone(e) => write!(f, "Invalid Timezone: due to {}", e),
+            MutationError::NoDefaultTimezone => write!(
+                f,
+                "Guild does not have a default timezone set. Please provide one"
+            ),
+            MutationError::InvalidDay(e) => write!(f, "Invalid Day: {}", e),
+            MutationError::InvalidYear(e) => write!(f, "Invalid Year: {}", e),
+            MutationError::NoNextOccurrence => write!(
+                f,
+                "Could not work out when the next birthday is in that timezone"
+            ),
+            MutationError::UnknownGuild => write!(f, "No birthdays found in this server"),
+            MutationError::NotRegistered => {
+                write!(f, "User is not registered with the birthday service")
+            }
+            MutationError::NothingToRestore => write!(f, "No recently removed birthday found"),
+            MutationError::AlreadyRegistered(_) => {
+                write!(f, "A birthday is already registered for that user")
+            }
+            MutationError::NotSaved(e) => {
+                write!(f, "The change was made but could not be saved: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MutationError {}
+
+/// Everything needed to register a birthday
+pub struct BirthdayRequest {
+    pub user_id: u64,
+    /// MM/DD
+    pub day: String,
+    /// Falls back to the guild default when missing
+    pub timezone: Option<String>,
+    pub private: bool,
+    pub year: Option<i32>,
+}
+
+/// Waits for the change to be on disk where the storage promises that, which has to happen
+/// after the guild's lock is let go as the write reads the guild
+async fn persist(storage: &dyn Storage) -> Result<(), MutationError> {
+    storage
+        .persist()
+        .await
+        .map_err(|e| MutationError::NotSaved(e.to_string()))
+}
+
+/// Event handlers have nobody to report a failed save to
+async fn persist_or_warn(storage: &dyn Storage, guild_id: u64) {
+    if let Err(e) = storage.persist().await {
+        warn!(guild_id, error = %e, "Could not save the change");
+    }
+}
+
+fn parse_timezone(timezone: &str) -> Result<Tz, MutationError> {
+    Tz::from_str(timezone).map_err(|e| MutationError::InvalidTimezone(e.to_string()))
+}
+
+pub async fn set_birthday(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    request: BirthdayRequest,
+) -> Result<Arc<BirthdayInfo>, MutationError> {
+    let (month, day) = parse_day(&request.day).map_err(MutationError::InvalidDay)?;
+    if let Some(year) = request.year {
+        validate_year(year).map_err(MutationError::InvalidYear)?;
+    }
+    let explicit_timezone = request
+        .timezone
+        .as_deref()
+        .map(parse_timezone)
+        .transpose()?;
+
+    let new_entry = {
+        let mut guild_data_mut = state.guild_map.write().await;
+        let guild_entry = guild_data_mut.entry(guild_id).or_default();
+        let mut guild_data_write = guild_entry.rw_lock.write().await;
+
+        let tz = explicit_timezone
+            .or(guild_data_write.timezone)
+            .ok_or(MutationError::NoDefaultTimezone)?;
+
+        let datetime = BirthdayInfo::next_occurrence(month, day, tz, Utc::now())
+            .ok_or(MutationError::NoNextOccurrence)?;
+
+        let new_entry = Arc::new(BirthdayInfo {
+            associated_user: request.user_id,
+            datetime,
+            timezone: Some(tz),
+            private: request.private,
+            year: request.year,
+            name: None,
+            leap_day: (month, day) == (2, 29),
+        });
+
+        let _ = guild_data_write
+            .birthday_schedule
+            .insert(Arc::clone(&new_entry));
+        new_entry
+    };
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await?;
+    Ok(new_entry)
+}
+
+/// Moves a birthday into the guild's trash
+pub async fn remove_birthday(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    user_id: u64,
+    deleted_by: u64,
+) -> Result<Arc<BirthdayInfo>, MutationError> {
+    let removed = {
+        let reader = state.guild_map.read().await;
+        let guild_data = reader.get(&guild_id).ok_or(MutationError::UnknownGuild)?;
+        let removed = guild_data
+            .rw_lock
+            .write()
+            .await
+            .trash_birthday(user_id, deleted_by)
+            .ok_or(MutationError::NotRegistered)?;
+        removed
+    };
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await?;
+    Ok(removed)
+}
+
+/// Sets the guild default timezone, None clears it
+pub async fn set_timezone(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    timezone: Option<&str>,
+) -> Result<Option<Tz>, MutationError> {
+    let timezone = timezone.map(parse_timezone).transpose()?;
+
+    {
+        let mut guild_data_mut = state.guild_map.write().await;
+        let guild_entry = guild_data_mut.entry(guild_id).or_default();
+        guild_entry.rw_lock.write().await.timezone = timezone;
+    }
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await?;
+    Ok(timezone)
+}
+
+/// Sets the channel announcements go to, None clears it
+pub async fn set_announcement_channel(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    channel_id: Option<u64>,
+) -> Result<(), MutationError> {
+    {
+        let mut guild_data_mut = state.guild_map.write().await;
+        let guild_entry = guild_data_mut.entry(guild_id).or_default();
+        let mut guild_entry_write = guild_entry.rw_lock.write().await;
+        guild_entry_write.announcement_channel = channel_id;
+        guild_entry_write.misconfigured = None;
+    }
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await
+}
+
+/// Records why birthdays cannot go to the guild's announcement channel, None once it works
+/// again. Returns whether that changed anything.
+pub async fn set_misconfigured(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    reason: Option<String>,
+) -> bool {
+    let guild_data = match state.guild_map.read().await.get(&guild_id) {
+        Some(guild_data) => Arc::clone(guild_data),
+        None => return false,
+    };
+    {
+        let mut writer = guild_data.rw_lock.write().await;
+        if writer.misconfigured == reason {
+            return false;
+        }
+        writer.misconfigured = reason;
+    }
+    storage.mark_dirty(guild_id);
+    persist_or_warn(storage, guild_id).await;
+    true
+}
+
+/// Puts back the most recent removal made by the given user. A birthday registered for the same
+/// user since then is kept, along with the trashed copy.
+pub async fn undo_removal(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    deleted_by: u64,
+) -> Result<Arc<BirthdayInfo>, MutationError> {
+    let restored = {
+        let reader = state.guild_map.read().await;
+        let guild_data = reader.get(&guild_id).ok_or(MutationError::UnknownGuild)?;
+        let mut guild_writer = guild_data.rw_lock.write().await;
+        if guild_writer.trash.purge_expired(Utc::now()) > 0 {
+            storage.mark_dirty(guild_id);
+        }
+
+        let trashed = guild_writer
+            .trash
+            .take_latest_by(deleted_by)
+            .ok_or(MutationError::NothingToRestore)?;
+        if guild_writer
+            .birthday_schedule
+            .get(trashed.info.associated_user)
+            .is_some()
+        {
+            guild_writer.trash.push(trashed.clone());
+            return Err(MutationError::AlreadyRegistered(trashed));
+        }
+        guild_writer.restore_birthday(trashed)
+    };
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await?;
+    Ok(restored)
+}
+
+/// Puts back the removed birthday of the given user
+pub async fn restore_removed(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    user_id: u64,
+) -> Result<Arc<BirthdayInfo>, MutationError> {
+    let restored = {
+        let reader = state.guild_map.read().await;
+        let guild_data = reader.get(&guild_id).ok_or(MutationError::UnknownGuild)?;
+        let mut guild_writer = guild_data.rw_lock.write().await;
+        if guild_writer.trash.purge_expired(Utc::now()) > 0 {
+            storage.mark_dirty(guild_id);
+        }
+
+        let trashed = guild_writer
+            .trash
+            .take_user(user_id)
+            .ok_or(MutationError::NothingToRestore)?;
+        if guild_writer.birthday_schedule.get(user_id).is_some() {
+            guild_writer.trash.push(trashed.clone());
+            return Err(MutationError::AlreadyRegistered(trashed));
+        }
+        guild_writer.restore_birthday(trashed)
+    };
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await?;
+    Ok(restored)
+}
+
+/// Writes a confirmed import, returning how many rows were applied and why any were not
+pub async fn apply_import(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    plan: &ImportPlan,
+    overwrite_conflicts: bool,
+) -> Result<ImportOutcome, MutationError> {
+    let outcome = {
+        let mut guild_data_mut = state.guild_map.write().await;
+        let guild_entry = guild_data_mut.entry(guild_id).or_default();
+        let outcome = plan.apply(&mut *guild_entry.rw_lock.write().await, overwrite_conflicts);
+        outcome
+    };
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await?;
+    Ok(outcome)
+}
+
+/// Returns the guild's feed token, making one first if feeds are off or a new one is asked for
+pub async fn feed_token(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    rotate: bool,
+) -> Result<String, MutationError> {
+    let token = {
+        let mut guild_data_mut = state.guild_map.write().await;
+        let guild_entry = guild_data_mut.entry(guild_id).or_default();
+        let mut guild_entry_write = guild_entry.rw_lock.write().await;
+
+        match &guild_entry_write.feed_token {
+            Some(token) if !rotate => return Ok(token.clone()),
+            _ => {
+                let token = generate_token();
+                guild_entry_write.feed_token = Some(token.clone());
+                token
+            }
+        }
+    };
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await?;
+    Ok(token)
+}
+
+/// Turns the guild's feeds off, breaking the old links
+pub async fn disable_feeds(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+) -> Result<(), MutationError> {
+    let disabled = match state.guild_map.read().await.get(&guild_id) {
+        Some(guild_data) => guild_data.rw_lock.write().await.feed_token.take().is_some(),
+        None => false,
+    };
+    if disabled {
+        storage.mark_dirty(guild_id);
+        persist(storage).await?;
+    }
+    Ok(())
+}
+
+/// Sets what happens to birthdays when members leave, None leaves it as is. Returns the policy
+/// in effect.
+pub async fn set_departure_policy(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    policy: Option<DeparturePolicy>,
+) -> Result<DeparturePolicy, MutationError> {
+    let policy = {
+        let mut guild_data_mut = state.guild_map.write().await;
+        let guild_entry = guild_data_mut.entry(guild_id).or_default();
+        let mut guild_entry_write = guild_entry.rw_lock.write().await;
+
+        let policy = match policy {
+            Some(policy) => policy,
+            None => return Ok(guild_entry_write.departure_policy),
+        };
+        guild_entry_write.departure_policy = policy;
+        policy
+    };
+
+    storage.mark_dirty(guild_id);
+    persist(storage).await?;
+    Ok(policy)
+}
+
+/// The bot was kicked from the guild or the guild was deleted. Under the delete policy the
+/// guild's data goes with it, otherwise it is kept unannounced until the bot is added back.
+pub async fn guild_removed(state: &ApplicationState, storage: &dyn Storage, guild_id: u64) {
+    {
+        let mut guild_map = state.guild_map.write().await;
+        let policy = match guild_map.get(&guild_id) {
+            Some(guild_data) => guild_data.rw_lock.read().await.departure_policy,
+            None => return,
+        };
+        if policy == DeparturePolicy::Delete {
+            guild_map.remove(&guild_id);
+            info!(guild_id, "Removed from guild, deleted its birthdays");
+        } else {
+            let mut writer = guild_map[&guild_id].rw_lock.write().await;
+            writer.removed_at = Some(Utc::now());
+            info!(
+                guild_id,
+                ?policy,
+                "Removed from guild, keeping its birthdays"
+            );
+        }
+    }
+    storage.mark_dirty(guild_id);
+    persist_or_warn(storage, guild_id).await;
+}
+
+/// Treats every kept guild missing from a new gateway session as removed, for when the bot was
+/// kicked while it was offline. Only guilds on the session's shard are looked at.
+pub async fn catch_up_removals(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    present: &HashSet<u64>,
+    shard: Option<[u64; 2]>,
+) {
+    let on_shard = |guild_id: u64| match shard {
+        // How Discord spreads guilds over shards
+        Some([shard_id, shard_count]) if shard_count > 1 => {
+            (guild_id >> 22) % shard_count == shard_id
+        }
+        _ => true,
+    };
+    let missing: Vec<(u64, Arc<RWGuildData>)> = state
+        .guild_map
+        .read()
+        .await
+        .iter()
+        .filter(|(guild_id, _)| on_shard(**guild_id) && !present.contains(guild_id))
+        .map(|(guild_id, guild_data)| (*guild_id, Arc::clone(guild_data)))
+        .collect();
+    for (guild_id, guild_data) in missing {
+        if guild_data.rw_lock.read().await.removed_at.is_none() {
+            guild_removed(state, storage, guild_id).await;
+        }
+    }
+}
+
+/// Sent for every guild the bot is in when it connects, so only a guild marked as removed
+/// needs anything doing
+pub async fn guild_available(state: &ApplicationState, storage: &dyn Storage, guild_id: u64) {
+    let removed_at = match state.guild_map.read().await.get(&guild_id) {
+        Some(guild_data) => guild_data.rw_lock.write().await.removed_at.take(),
+        None => return,
+    };
+    if let Some(removed_at) = removed_at {
+        info!(guild_id, %removed_at, "Added back to guild, announcing its birthdays again");
+        storage.mark_dirty(guild_id);
+        persist_or_warn(storage, guild_id).await;
+    }
+}
+
+/// Applies the guild's departure policy to a member who left
+pub async fn member_left(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    user_id: u64,
+) {
+    let policy = match state.guild_map.read().await.get(&guild_id) {
+        Some(guild_data) => {
+            let mut writer = guild_data.rw_lock.write().await;
+            match writer.member_left(user_id) {
+                true => Some(writer.departure_policy),
+                false => None,
+            }
+        }
+        None => return,
+    };
+    if let Some(policy) = policy {
+        info!(
+            guild_id,
+            user_id,
+            ?policy,
+            "Member left, applied the departure policy"
+        );
+        storage.mark_dirty(guild_id);
+        persist_or_warn(storage, guild_id).await;
+    }
+}
+
+/// Undoes the departure policy for a member who rejoined
+pub async fn member_joined(
+    state: &ApplicationState,
+    storage: &dyn Storage,
+    guild_id: u64,
+    user_id: u64,
+) {
+    let returned = match state.guild_map.read().await.get(&guild_id) {
+        Some(guild_data) => guild_data.rw_lock.write().await.member_returned(user_id),
+        None => return,
+    };
+    if returned {
+        info!(
+            guild_id,
+            user_id, "Member rejoined, restored their birthday"
+        );
+        storage.mark_dirty(guild_id);
+        persist_or_warn(storage, guild_id).await;
+    }
+}
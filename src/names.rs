@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use poise::futures_util::{stream, StreamExt};
+use serenity::{http::Http, prelude::SerenityError};
+use tokio::sync::RwLock;
+use tracing::warn;
+
+use crate::structs::BirthdayInfo;
+
+/// How long a fetched member name is trusted before asking Discord again
+pub const DEFAULT_NAME_TTL: Duration = Duration::from_secs(600);
+/// How many member requests are in flight at once, serenity queues the rest behind its ratelimiter
+pub const MAX_CONCURRENT_FETCHES: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemberName {
+    Present(String),
+    /// Discord says the user is no longer in the guild
+    Departed,
+    /// The fetch failed for some other reason, most likely temporarily
+    Unknown,
+}
+
+impl MemberName {
+    pub fn display(&self) -> &str {
+        match self {
+            MemberName::Present(name) => name,
+            MemberName::Departed => "Former member",
+            MemberName::Unknown => "Unknown member",
+        }
+    }
+}
+
+struct CachedName {
+    name: MemberName,
+    fetched_at: Instant,
+}
+
+/// Shared cache of guild member display names
+pub struct NameResolver {
+    cache: RwLock<HashMap<(u64, u64), CachedName>>,
+    ttl: Duration,
+}
+
+impl Default for NameResolver {
+    fn default() -> Self {
+        Self::new(DEFAULT_NAME_TTL)
+    }
+}
+
+impl NameResolver {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            cache: RwLock::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Names every entry, using the stored name for entries that are not linked to a member
+    pub async fn resolve_entries<'a>(
+        &self,
+        http: &Http,
+        guild_id: u64,
+        entries: impl IntoIterator<Item = &'a BirthdayInfo>,
+    ) -> HashMap<u64, MemberName> {
+        let mut unlinked = HashMap::new();
+        let mut user_ids = vec![];
+        for info in entries {
+            match &info.name {
+                Some(name) => {
+                    unlinked.insert(info.associated_user, MemberName::Present(name.clone()));
+                }
+                None => user_ids.push(info.associated_user),
+            }
+        }
+        let mut res = self.resolve(http, guild_id, &user_ids).await;
+        res.extend(unlinked);
+        res
+    }
+
+    /// Resolves the display names of the given users, only asking Discord for ones not cached
+    pub async fn resolve(
+        &self,
+        http: &Http,
+        guild_id: u64,
+        user_ids: &[u64],
+    ) -> HashMap<u64, MemberName> {
+        self.resolve_with(guild_id, user_ids, Instant::now(), |user_id| {
+            fetch_name(http, guild_id, user_id)
+        })
+        .await
+    }
+
+    /// Resolves names as of `now`, calling `fetch` once for each user that is not cached
+    async fn resolve_with<F, Fut>(
+        &self,
+        guild_id: u64,
+        user_ids: &[u64],
+        now: Instant,
+        fetch: F,
+    ) -> HashMap<u64, MemberName>
+    where
+        F: Fn(u64) -> Fut,
+        Fut: Future<Output = MemberName>,
+    {
+        let ttl = self.ttl;
+        let fresh = |cached: &CachedName| now.saturating_duration_since(cached.fetched_at) < ttl;
+        let mut res = HashMap::with_capacity(user_ids.len());
+        let mut missing = vec![];
+
+        {
+            let reader = self.cache.read().await;
+            for user_id in user_ids {
+                match reader.get(&(guild_id, *user_id)) {
+                    Some(cached) if fresh(cached) => {
+                        res.insert(*user_id, cached.name.clone());
+                    }
+                    _ => missing.push(*user_id),
+                }
+            }
+        }
+
+        if missing.is_empty() {
+            return res;
+        }
+        missing.sort_unstable();
+        missing.dedup();
+
+        let fetched: Vec<(u64, MemberName)> = stream::iter(missing)
+            .map(|user_id| {
+                let name = fetch(user_id);
+                async move { (user_id, name.await) }
+            })
+            .buffer_unordered(MAX_CONCURRENT_FETCHES)
+            .collect()
+            .await;
+
+        let mut writer = self.cache.write().await;
+        writer.retain(|_, cached| fresh(cached));
+        for (user_id, name) in fetched {
+            // Failures are not cached so the next lookup tries again
+            if name != MemberName::Unknown {
+                writer.insert(
+                    (guild_id, user_id),
+                    CachedName {
+                        name: name.clone(),
+                        fetched_at: now,
+                    },
+                );
+            }
+            res.insert(user_id, name);
+        }
+        res
+    }
+}
+
+async fn fetch_name(http: &Http, guild_id: u64, user_id: u64) -> MemberName {
+    match http.get_member(guild_id, user_id).await {
+        Ok(member) => MemberName::Present(member.display_name().to_string()),
+        Err(e) if is_not_found(&e) => MemberName::Departed,
+        Err(e) => {
+            warn!(guild_id, user_id, error = %e, "Could not fetch member");
+            MemberName::Unknown
+        }
+    }
+}
+
+fn is_not_found(e: &SerenityError) -> bool {
+    match e {
+        SerenityError::Http(http_error) => http_error
+            .status_code()
+            .map(|code| code.as_u16() == 404)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Resolves through a fake fetch, returning the names and which users were fetched
+    async fn resolve_counting(
+        resolver: &NameResolver,
+        user_ids: &[u64],
+        now: Instant,
+    ) -> (HashMap<u64, MemberName>, Vec<u64>) {
+        let fetched = Mutex::new(vec![]);
+        let names = resolver
+            .resolve_with(1, user_ids, now, |user_id| {
+                fetched.lock().unwrap().push(user_id);
+                async move { MemberName::Present(format!("member {}", user_id)) }
+            })
+            .await;
+        let mut fetched = fetched.into_inner().unwrap();
+        fetched.sort_unstable();
+        (names, fetched)
+    }
+
+    #[tokio::test]
+    async fn names_are_fetched_again_once_they_expire() {
+        let resolver = NameResolver::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        let (names, fetched) = resolve_counting(&resolver, &[10], start).await;
+        assert_eq!(names[&10], MemberName::Present("member 10".to_string()));
+        assert_eq!(fetched, [10]);
+
+        let (_, fetched) =
+            resolve_counting(&resolver, &[10], start + Duration::from_secs(30)).await;
+        assert!(fetched.is_empty());
+
+        let (names, fetched) =
+            resolve_counting(&resolver, &[10], start + Duration::from_secs(61)).await;
+        assert_eq!(names[&10], MemberName::Present("member 10".to_string()));
+        assert_eq!(fetched, [10]);
+    }
+
+    #[tokio::test]
+    async fn duplicate_ids_are_fetched_once() {
+        let resolver = NameResolver::default();
+        let (names, fetched) =
+            resolve_counting(&resolver, &[20, 10, 20, 10, 20], Instant::now()).await;
+        assert_eq!(names.len(), 2);
+        assert_eq!(fetched, [10, 20]);
+    }
+}
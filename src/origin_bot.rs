@@ -1,54 +1,70 @@
-use std::{path::PathBuf, sync::Arc};
+use std::sync::Arc;
 
 use crate::{
-    commands::get_commands,
+    channels::check_all,
+    commands::{get_commands, hooks},
+    config::Config,
     cron::bday_crunching,
-    persistence::SaveManager,
-    structs::{ApplicationState, Data},
+    health::Health,
+    metrics::Metrics,
+    names::NameResolver,
+    shutdown::{run_on_signal, Shutdown, ShutdownPlan},
+    storage,
+    structs::Data,
+    web::{serve_admin, serve_feeds, WebSettings, WebState},
 };
 use poise::serenity_prelude::Activity;
 use serenity::prelude::GatewayIntents;
-use tokio::fs;
+use tokio::sync::oneshot;
 
 pub async fn start_bot(
     token: String,
     intents: GatewayIntents,
-    save_location: PathBuf,
-) -> Result<(), serenity::Error> {
-    let try_load = fs::read_to_string(save_location.clone()).await;
-
-    let state = if let Ok(loaded_data) = try_load {
-        match serde_json::from_str::<ApplicationState>(&loaded_data) {
-            Ok(state) => state,
-            Err(e) => {
-                println!("{}", e);
-                Default::default()
-            }
-        }
-    } else {
-        Default::default()
-    };
+    config: Config,
+    web_settings: WebSettings,
+) -> anyhow::Result<()> {
+    let metrics = Arc::new(Metrics::new());
 
-    let application_state = Arc::new(state);
+    let health = Arc::new(Health::new(config.scheduler.tick_interval()));
 
-    let saver = Arc::new(SaveManager::new(
-        Arc::clone(&application_state),
-        save_location,
-    ));
+    let (application_state, storage) =
+        storage::open(&config.storage, Arc::clone(&metrics), Arc::clone(&health)).await?;
+
+    let names = Arc::new(NameResolver::default());
+
+    let templates = Arc::new(config.templates);
+
+    let feed_base_url = web_settings.feed_base_url();
+
+    let shutdown = Shutdown::default();
+
+    let track_members = config.discord.track_members;
 
     let cron_data = Data {
         state: Arc::clone(&application_state),
-        saver: Arc::clone(&saver),
+        storage: Arc::clone(&storage),
+        names: Arc::clone(&names),
+        metrics: Arc::clone(&metrics),
+        health: Arc::clone(&health),
+        templates: Arc::clone(&templates),
+        shutdown: shutdown.clone(),
+        feed_base_url: feed_base_url.clone(),
+        track_members,
     };
 
     let framework_builder = poise::Framework::builder()
         .options(poise::FrameworkOptions {
             commands: get_commands(),
+            pre_command: hooks::pre_command,
+            post_command: hooks::post_command,
+            on_error: hooks::on_error,
+            event_handler: hooks::event_handler,
+            command_check: Some(hooks::command_check),
             ..Default::default()
         })
         .token(token)
         .intents(intents)
-        .setup(|ctx, _ready, framework| {
+        .setup(move |ctx, _ready, framework| {
             Box::pin(async move {
                 ctx.set_presence(
                     Some(Activity::watching("for celebrations")),
@@ -58,7 +74,14 @@ pub async fn start_bot(
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
                 Ok(Data {
                     state: application_state,
-                    saver,
+                    storage,
+                    names,
+                    metrics,
+                    health,
+                    templates,
+                    shutdown,
+                    feed_base_url,
+                    track_members,
                 })
             })
         });
@@ -67,7 +90,65 @@ pub async fn start_bot(
 
     let http_cache = Arc::clone(&framework.client().cache_and_http);
 
-    tokio::spawn(bday_crunching(http_cache, cron_data));
+    let web_state = Arc::new(WebState {
+        state: Arc::clone(&cron_data.state),
+        storage: Arc::clone(&cron_data.storage),
+        http: Arc::clone(&http_cache.http),
+        names: Arc::clone(&cron_data.names),
+        metrics: Arc::clone(&cron_data.metrics),
+        health: Arc::clone(&cron_data.health),
+    });
+
+    let health = Arc::clone(&cron_data.health);
+    let shutdown = cron_data.shutdown.clone();
+    let storage = Arc::clone(&cron_data.storage);
 
-    framework.start().await
+    if let Some(port) = web_settings.feed_port {
+        health.spawn_task("feeds", serve_feeds(port, Arc::clone(&web_state)));
+    }
+
+    if let Some(port) = web_settings.admin_port {
+        health.spawn_task(
+            "admin",
+            serve_admin(port, web_settings.admin_token, web_state),
+        );
+    }
+
+    // Runs once and is done, so it is not tracked as a background task
+    tokio::spawn(check_all(
+        Arc::clone(&http_cache.http),
+        Arc::clone(&cron_data.state),
+        Arc::clone(&storage),
+    ));
+
+    let cron = health.spawn_task(
+        "cron",
+        bday_crunching(http_cache, cron_data, config.scheduler.tick_interval()),
+    );
+
+    let (client_stopped, stopped) = oneshot::channel();
+    let mut shutdown_sequence = tokio::spawn(run_on_signal(
+        ShutdownPlan {
+            shutdown,
+            health,
+            cron,
+            storage,
+            shard_manager: Arc::clone(framework.shard_manager()),
+            timeout: config.shutdown.timeout(),
+        },
+        stopped,
+    ));
+
+    // The client does not always return once its shards are closed, so a finished shutdown
+    // ends the bot either way
+    tokio::select! {
+        res = framework.start() => {
+            // However the client stopped, commands are drained and the state saved before exiting
+            let _ = client_stopped.send(());
+            let _ = shutdown_sequence.await;
+            res?
+        }
+        _ = &mut shutdown_sequence => {}
+    }
+    Ok(())
 }
@@ -1,53 +1,218 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
+use chrono::Utc;
 use tokio::{
-    fs,
-    sync::watch::{self, Receiver, Sender},
+    fs::{self, File},
+    io::AsyncWriteExt,
 };
 
-use crate::structs::ApplicationState;
+use tracing::{debug, info, warn};
+
+use crate::config::StorageConfig;
+
+// The file handling shared by the JSON storage, restores and migrations: atomic writes and
+// rotating backups
+
+/// Sorts the same alphabetically as by time, so the newest backup is always last
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
 
-// Saving only, load is handled by app root
-pub struct SaveManager {
-    watch_sender: Sender<()>,
+/// Subfolder of the backups holding the copies taken before a restore or migration
+const KEPT_DIR: &str = "kept";
+
+/// Where backups of the save go and how many of them stick around
+pub struct BackupPolicy {
+    pub dir: PathBuf,
+    /// File name of the save without its extension, every backup starts with it
+    prefix: String,
+    kept: usize,
+    interval: Duration,
 }
 
-impl SaveManager {
-    pub fn new(state: Arc<ApplicationState>, location: PathBuf) -> Self {
-        let (send, recv) = watch::channel(());
-        tokio::spawn(saver(recv, location, state));
+impl BackupPolicy {
+    pub fn new(storage: &StorageConfig) -> Self {
+        Self {
+            dir: storage.backup_dir(),
+            prefix: backup_prefix(&storage.save_location),
+            kept: storage.backups_kept,
+            interval: storage.backup_interval(),
+        }
+    }
 
-        Self { watch_sender: send }
+    /// Whether enough time has passed since the last backup, given when that was
+    pub fn is_due(&self, last_backup: Option<Instant>) -> bool {
+        self.kept > 0 && last_backup.is_none_or(|taken| taken.elapsed() >= self.interval)
     }
 
-    pub fn save(&self) {
-        let _ = self.watch_sender.send(());
+    /// Copies the freshly written save into the backups and drops the oldest ones past the limit
+    pub async fn take(&self, location: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let name = format!(
+            "{}-{}.json",
+            self.prefix,
+            Utc::now().format(BACKUP_TIMESTAMP_FORMAT)
+        );
+        let bytes = fs::read(location).await?;
+        write_atomically(&self.dir.join(&name), &bytes).await?;
+        debug!(backup = name, "Backed up application state");
+
+        let backups = list_backups(&self.dir, &self.prefix).await?;
+        let excess = backups.len().saturating_sub(self.kept);
+        for old in &backups[..excess] {
+            if let Err(e) = fs::remove_file(self.dir.join(old)).await {
+                warn!(backup = ?old, error = %e, "Could not remove old backup");
+            }
+        }
+        Ok(())
     }
 }
 
-async fn saver(
-    mut recv: Receiver<()>,
-    location: PathBuf,
-    state: Arc<ApplicationState>,
-) -> anyhow::Result<()> {
-    loop {
-        recv.changed().await?;
+/// Writes to a temporary file next to the target, flushes it to disk and renames it over the
+/// target, so a crash part way through leaves the old file untouched
+pub async fn write_atomically(location: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = location.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_location = location.with_file_name(temp_name);
 
-        let serialized = serde_json::to_string_pretty(&state);
+    let mut file = File::create(&temp_location).await?;
+    file.write_all(bytes).await?;
+    file.sync_all().await?;
+    drop(file);
 
-        let serialized = match serialized {
-            Ok(serialized) => serialized,
-            Err(_) => {
-                println!("Could not serialize application state for saving.");
-                continue;
-            }
-        };
+    fs::rename(&temp_location, location).await?;
 
-        match fs::write(location.clone(), serialized).await {
-            Ok(_) => {}
-            Err(_) => {
-                println!("Could not save application state.");
-            }
+    // The rename itself only survives a crash once the directory is flushed too
+    #[cfg(unix)]
+    {
+        let parent = match location.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
         };
+        File::open(parent).await?.sync_all().await?;
     }
+    Ok(())
+}
+
+fn backup_prefix(save_location: &Path) -> String {
+    save_location
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Backup file names in the directory, oldest first
+async fn list_backups(dir: &Path, prefix: &str) -> std::io::Result<Vec<OsString>> {
+    let mut backups = Vec::new();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+        Err(e) => return Err(e),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        if name_str.starts_with(&format!("{}-", prefix)) && name_str.ends_with(".json") {
+            backups.push(name);
+        }
+    }
+    backups.sort();
+    Ok(backups)
+}
+
+/// Puts a backup in place of the save, picked by file name or "latest" for the newest one.
+/// The save being replaced is backed up first so the restore can itself be undone.
+pub async fn restore_backup(storage: &StorageConfig, name: &str) -> anyhow::Result<PathBuf> {
+    let dir = storage.backup_dir();
+    let prefix = backup_prefix(&storage.save_location);
+    let backups = list_backups(&dir, &prefix).await?;
+
+    let chosen = if name == "latest" {
+        backups.last()
+    } else {
+        backups
+            .iter()
+            .find(|backup| backup.to_string_lossy() == name)
+    };
+    let chosen = match chosen {
+        Some(chosen) => dir.join(chosen),
+        None if backups.is_empty() => {
+            anyhow::bail!("There are no backups in {}", dir.display())
+        }
+        None => {
+            let available = backups
+                .iter()
+                .map(|backup| backup.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ");
+            anyhow::bail!("No backup named {}, the backups are: {}", name, available)
+        }
+    };
+
+    let bytes = fs::read(&chosen).await?;
+    if fs::try_exists(&storage.save_location).await? {
+        let replaced = fs::read(&storage.save_location).await?;
+        let kept = keep_copy(storage, "before-restore", &replaced).await?;
+        info!(backup = %kept.display(), "Kept the save being replaced");
+    }
+    write_atomically(&storage.save_location, &bytes).await?;
+    Ok(chosen)
+}
+
+/// Writes a one off backup with a label saying why it was taken. These go in a folder of their
+/// own inside the backups, so the rotation never removes them. As nothing clears them out, a
+/// copy with the same label and contents is handed back instead of being written again.
+pub async fn keep_copy(
+    storage: &StorageConfig,
+    label: &str,
+    bytes: &[u8],
+) -> std::io::Result<PathBuf> {
+    let dir = storage.backup_dir().join(KEPT_DIR);
+    fs::create_dir_all(&dir).await?;
+    let prefix = backup_prefix(&storage.save_location);
+    if let Some(existing) = find_kept_copy(&dir, &prefix, label, bytes).await? {
+        return Ok(existing);
+    }
+    let location = dir.join(format!(
+        "{}-{}-{}.json",
+        prefix,
+        Utc::now().format(BACKUP_TIMESTAMP_FORMAT),
+        label
+    ));
+    write_atomically(&location, bytes).await?;
+    Ok(location)
+}
+
+async fn find_kept_copy(
+    dir: &Path,
+    prefix: &str,
+    label: &str,
+    bytes: &[u8],
+) -> std::io::Result<Option<PathBuf>> {
+    let suffix = format!("-{}.json", label);
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(prefix)
+            && name.ends_with(&suffix)
+            && fs::read(entry.path()).await? == bytes
+        {
+            return Ok(Some(entry.path()));
+        }
+    }
+    Ok(None)
+}
+
+/// Moves a file that could not be read into the quarantine directory, stamped so repeated
+/// failures do not overwrite each other
+pub async fn quarantine(path: &Path, dir: &Path) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir).await?;
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", Utc::now().format(BACKUP_TIMESTAMP_FORMAT)));
+    let target = dir.join(name);
+    fs::rename(path, &target).await?;
+    Ok(target)
 }
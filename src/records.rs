@@ -0,0 +1,71 @@
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::structs::BirthdayInfo;
+
+/// One birthday in the bulk CSV/JSON format
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BirthdayRecord {
+    /// A user ID, or when importing also a member name
+    pub user: String,
+    /// MM/DD
+    pub date: String,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub year: Option<i32>,
+}
+
+impl BirthdayRecord {
+    pub fn from_info(info: &BirthdayInfo, guild_timezone: Option<Tz>) -> Self {
+        Self {
+            user: match &info.name {
+                Some(name) => name.clone(),
+                None => info.associated_user.to_string(),
+            },
+            date: {
+                let (month, day) = info.month_day(guild_timezone);
+                format!("{:0>2}/{:0>2}", month, day)
+            },
+            timezone: info
+                .timezone
+                .or(guild_timezone)
+                .map(|tz| tz.name().to_string()),
+            year: info.year,
+        }
+    }
+}
+
+pub fn write_csv(records: &[BirthdayRecord]) -> Result<String, csv::Error> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for record in records {
+        writer.serialize(record)?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+pub fn write_json(records: &[BirthdayRecord]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(records)
+}
+
+/// Reads every row, keeping the rows that could not be read as errors so they can be reported
+pub fn read_csv(bytes: &[u8]) -> Vec<Result<BirthdayRecord, String>> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(bytes)
+        .deserialize::<BirthdayRecord>()
+        .map(|row| row.map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Reads a JSON array of records, entries that do not fit the format are reported individually
+pub fn read_json(bytes: &[u8]) -> Result<Vec<Result<BirthdayRecord, String>>, String> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(bytes)
+        .map_err(|e| format!("Not a JSON array of birthdays: {}", e))?;
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+        .collect())
+}
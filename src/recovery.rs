@@ -0,0 +1,362 @@
+use std::{fmt::Write, path::Path};
+
+use serde_json::{Map, Value};
+use tokio::fs;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::StorageConfig,
+    migrations::{migrate, take_version, VersionedSave},
+    persistence::{quarantine, write_atomically},
+    structs::{ApplicationState, StateSnapshot},
+    validation::{read_guild_map, LoadReport},
+};
+
+/// What a recovery got back and what it could not
+pub struct RecoveryReport {
+    pub recovered: Vec<u64>,
+    /// Guild keys from the save that could not be read, with why
+    pub lost: Vec<(String, String)>,
+    /// Set when the save was unreadable as a whole, so nothing could be salvaged
+    pub unreadable: Option<String>,
+    /// Set when the save breaks off or stops making sense partway, so whatever came after that
+    /// point is lost along with the guild it happened in
+    pub cut_off: Option<String>,
+}
+
+impl RecoveryReport {
+    /// Written next to the quarantined save
+    fn render(&self, quarantined: &Path) -> String {
+        let mut report = format!("Recovery of {}\n", quarantined.display());
+        if let Some(error) = &self.unreadable {
+            let _ = writeln!(
+                report,
+                "The file could not be read at all, nothing was recovered: {}",
+                error
+            );
+            return report;
+        }
+        if let Some(error) = &self.cut_off {
+            let _ = writeln!(
+                report,
+                "The file could only be read up to a point, any guilds after it are lost: {}",
+                error
+            );
+        }
+        let _ = writeln!(report, "Recovered {} guilds", self.recovered.len());
+        let _ = writeln!(report, "Lost {} guilds", self.lost.len());
+        for (guild, error) in &self.lost {
+            let _ = writeln!(report, "  {}: {}", guild, error);
+        }
+        report
+    }
+}
+
+/// Moves the unreadable save aside, pulls out every guild that can still be read and writes
+/// those back as the new save. A report of what was lost is logged and written next to the
+/// quarantined file. A save from a newer build is left alone, as it is not broken.
+pub async fn recover(text: &str, config: &StorageConfig) -> anyhow::Result<ApplicationState> {
+    let (snapshot, report) = salvage(text, &config.save_location)?;
+
+    let quarantined = quarantine(&config.save_location, &config.quarantine_dir()).await?;
+    warn!(quarantined = %quarantined.display(), "Moved the unreadable save aside, recovering what can be read");
+
+    for (guild, error) in &report.lost {
+        error!(guild, error, "Could not recover guild");
+    }
+    match &report.unreadable {
+        Some(error) => error!(error, "Nothing could be recovered from the save"),
+        None => info!(
+            recovered = report.recovered.len(),
+            lost = report.lost.len(),
+            cut_off = report.cut_off,
+            "Recovered the save"
+        ),
+    }
+
+    let mut report_path = quarantined.clone().into_os_string();
+    report_path.push(".report.txt");
+    if let Err(e) = fs::write(&report_path, report.render(&quarantined)).await {
+        warn!(error = %e, "Could not write the recovery report");
+    }
+
+    // Written straight away, as otherwise a restart before the next change would find no save
+    let serialized = serde_json::to_vec_pretty(&VersionedSave::new(&snapshot))?;
+    write_atomically(&config.save_location, &serialized).await?;
+
+    Ok(snapshot.into())
+}
+
+/// Reads what it can from the save, brought up to the current version first. Fails only on
+/// a save that should not be recovered at all.
+fn salvage(text: &str, location: &Path) -> anyhow::Result<(StateSnapshot, RecoveryReport)> {
+    let mut report = RecoveryReport {
+        recovered: Vec::new(),
+        lost: Vec::new(),
+        unreadable: None,
+        cut_off: None,
+    };
+
+    let mut save = match serde_json::from_str::<Value>(text) {
+        Ok(Value::Object(save)) => save,
+        Ok(_) => {
+            report.unreadable = Some("The save is not a JSON object".to_string());
+            return Ok((StateSnapshot::default(), report));
+        }
+        // A truncated or damaged save is read a guild at a time instead, keeping every guild
+        // that still parses on its own
+        Err(e) => {
+            let save = scan_save(text, &mut report);
+            if !save.contains_key("guild_map") {
+                report.unreadable = Some(e.to_string());
+                return Ok((StateSnapshot::default(), report));
+            }
+            save
+        }
+    };
+    let version = take_version(&mut save)
+        .map_err(|e| anyhow::anyhow!("Not recovering {}: {}", location.display(), e))?;
+    if let Err(e) = migrate(&mut save, version, location) {
+        report.unreadable = Some(e.to_string());
+        return Ok((StateSnapshot::default(), report));
+    }
+    let guilds = match save.remove("guild_map") {
+        Some(Value::Object(guilds)) => guilds,
+        _ => {
+            report.unreadable = Some("There is no guild_map in the save".to_string());
+            return Ok((StateSnapshot::default(), report));
+        }
+    };
+
+    // Entries that were only repaired or dropped are logged, only whole guilds count as lost
+    let mut load_report = LoadReport::default();
+    let guild_map = read_guild_map(guilds, &mut load_report);
+    load_report.log();
+    for problem in load_report.problems {
+        if problem.dropped && problem.entry.is_none() {
+            report.lost.push((problem.guild, problem.detail));
+        }
+    }
+    report.recovered = guild_map.keys().copied().collect();
+    report.recovered.sort_unstable();
+
+    Ok((StateSnapshot { guild_map }, report))
+}
+
+/// Reads the top level of a save that is not valid JSON as a whole, stopping where it breaks
+fn scan_save(text: &str, report: &mut RecoveryReport) -> Map<String, Value> {
+    let mut save = Map::new();
+    let mut scanner = Scanner { text, pos: 0 };
+    if let Err(e) = scan_members(&mut scanner, &mut save, report) {
+        report.cut_off = Some(e);
+    }
+    save
+}
+
+fn scan_members(
+    scanner: &mut Scanner,
+    save: &mut Map<String, Value>,
+    report: &mut RecoveryReport,
+) -> Result<(), String> {
+    scanner.expect(b'{')?;
+    while let Some(key) = scanner.next_key()? {
+        if key == "guild_map" {
+            let mut guilds = Map::new();
+            let scanned = scan_guilds(scanner, &mut guilds, report);
+            save.insert(key, Value::Object(guilds));
+            scanned?;
+        } else {
+            let value = scanner.value()?;
+            let value = serde_json::from_str(value).map_err(|e| format!("{}: {}", key, e))?;
+            save.insert(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Keeps every guild that parses, a guild that does not is lost but the ones after it are not
+fn scan_guilds(
+    scanner: &mut Scanner,
+    guilds: &mut Map<String, Value>,
+    report: &mut RecoveryReport,
+) -> Result<(), String> {
+    scanner.expect(b'{')?;
+    while let Some(guild) = scanner.next_key()? {
+        let value = match scanner.value() {
+            Ok(value) => value,
+            Err(e) => {
+                report
+                    .lost
+                    .push((guild, format!("The save breaks off in it: {}", e)));
+                return Err(e);
+            }
+        };
+        match serde_json::from_str(value) {
+            Ok(value) => {
+                guilds.insert(guild, value);
+            }
+            Err(e) => report.lost.push((guild, e.to_string())),
+        }
+    }
+    Ok(())
+}
+
+/// Finds where JSON values start and end without parsing them, so the parts of a damaged file
+/// can be parsed one by one
+struct Scanner<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(byte) = self.text.as_bytes().get(self.pos) {
+            if !byte.is_ascii_whitespace() {
+                return Some(*byte);
+            }
+            self.pos += 1;
+        }
+        None
+    }
+
+    fn error(&self, what: &str) -> String {
+        if self.pos >= self.text.len() {
+            format!("the file ends while expecting {}", what)
+        } else {
+            format!("expected {} at byte {}", what, self.pos)
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("`{}`", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// The key of the next member of the object being read, None once it is closed
+    fn next_key(&mut self) -> Result<Option<String>, String> {
+        match self.peek() {
+            Some(b'}') => {
+                self.pos += 1;
+                return Ok(None);
+            }
+            Some(b',') => self.pos += 1,
+            _ => {}
+        }
+        let key = self.value()?;
+        let key = serde_json::from_str(key).map_err(|_| self.error("a key"))?;
+        self.expect(b':')?;
+        Ok(Some(key))
+    }
+
+    /// Moves past the value that starts here, returning its text
+    fn value(&mut self) -> Result<&'a str, String> {
+        let bytes = self.text.as_bytes();
+        let start = match self.peek() {
+            Some(_) => self.pos,
+            None => return Err(self.error("a value")),
+        };
+        match bytes[start] {
+            b'"' | b'{' | b'[' => {
+                let mut depth = 0;
+                let mut in_string = false;
+                loop {
+                    let byte = match bytes.get(self.pos) {
+                        Some(byte) => *byte,
+                        None => return Err(self.error("the rest of a value")),
+                    };
+                    self.pos += 1;
+                    match (in_string, byte) {
+                        (true, b'\\') => self.pos += 1,
+                        (true, b'"') => in_string = false,
+                        (false, b'"') => in_string = true,
+                        (false, b'{' | b'[') => depth += 1,
+                        (false, b'}' | b']') => depth -= 1,
+                        _ => {}
+                    }
+                    if !in_string && depth == 0 {
+                        break;
+                    }
+                }
+            }
+            // Numbers and literals run until whatever comes after them
+            _ => {
+                while let Some(byte) = bytes.get(self.pos) {
+                    if matches!(byte, b',' | b'}' | b']' | b':') || byte.is_ascii_whitespace() {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+            }
+        }
+        Ok(&self.text[start..self.pos])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V0_SAVE: &str = include_str!("../tests/fixtures/save-v0.json");
+    const V1_SAVE: &str = include_str!("../tests/fixtures/save-v1.json");
+
+    #[test]
+    fn older_saves_are_migrated_before_salvaging() {
+        let (snapshot, report) = salvage(V0_SAVE, Path::new("birthdays.json")).unwrap();
+        assert_eq!(report.recovered, vec![100]);
+        assert!(report.lost.is_empty());
+        assert!(snapshot.guild_map.contains_key(&100));
+    }
+
+    #[test]
+    fn the_guilds_before_a_cut_are_recovered() {
+        let mut save: serde_json::Value = serde_json::from_str(V1_SAVE).unwrap();
+        let guild = save["guild_map"]["100"].clone();
+        for guild_id in ["101", "102", "103"] {
+            save["guild_map"][guild_id] = guild.clone();
+        }
+        let text = serde_json::to_string_pretty(&save).unwrap();
+        // Cut partway through the last guild, as a write interrupted by a crash would
+        let cut = text.rfind("\"103\"").unwrap() + 40;
+
+        let (snapshot, report) = salvage(&text[..cut], Path::new("birthdays.json")).unwrap();
+        assert_eq!(report.recovered, vec![100, 101, 102]);
+        assert_eq!(report.lost.len(), 1);
+        assert_eq!(report.lost[0].0, "103");
+        assert!(report.cut_off.is_some());
+        assert!(report.unreadable.is_none());
+        assert!(snapshot.guild_map[&101]
+            .rw_lock
+            .birthday_schedule
+            .get(300000000000000000)
+            .is_some());
+    }
+
+    #[test]
+    fn a_damaged_guild_is_lost_but_the_ones_after_it_are_not() {
+        let mut save: serde_json::Value = serde_json::from_str(V1_SAVE).unwrap();
+        let guild = save["guild_map"]["100"].clone();
+        save["guild_map"]["101"] = guild.clone();
+        let text = serde_json::to_string_pretty(&save).unwrap();
+        // A stray byte in the first guild breaks the whole file for a plain parse
+        let damaged = text.replacen(
+            "\"announcement_channel\": 200",
+            "\"announcement_channel\": 2#0",
+            1,
+        );
+        assert!(serde_json::from_str::<Value>(&damaged).is_err());
+
+        let (_, report) = salvage(&damaged, Path::new("birthdays.json")).unwrap();
+        assert_eq!(report.recovered.len(), 1);
+        assert_eq!(report.lost.len(), 1);
+        assert!(report.cut_off.is_none());
+    }
+
+    #[test]
+    fn a_save_from_a_newer_build_is_not_salvaged() {
+        let newer = V1_SAVE.replacen("\"version\": 1", "\"version\": 99", 1);
+        assert!(salvage(&newer, Path::new("birthdays.json")).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::{sync::Arc, time::Duration};
+
+use serenity::{client::bridge::gateway::ShardManager, prelude::Mutex};
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{error, info, warn};
+
+use crate::{health::Health, storage::Storage};
+
+/// Tells the long running parts of the bot that it is going down, and keeps count of the
+/// commands still running so the final save can wait for them
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+    running_commands: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        let (running_commands, _) = watch::channel(0);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+            running_commands: Arc::new(running_commands),
+        }
+    }
+}
+
+/// Counts a command as running until it is dropped
+pub struct CommandGuard {
+    running_commands: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for CommandGuard {
+    fn drop(&mut self) {
+        self.running_commands.send_modify(|running| *running -= 1);
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        let _ = self.sender.send(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    pub fn command_started(&self) -> CommandGuard {
+        self.running_commands.send_modify(|running| *running += 1);
+        CommandGuard {
+            running_commands: Arc::clone(&self.running_commands),
+        }
+    }
+
+    /// Resolves once no command is running
+    pub async fn commands_finished(&self) {
+        let mut receiver = self.running_commands.subscribe();
+        let _ = receiver.wait_for(|running| *running == 0).await;
+    }
+
+    /// Resolves once shutdown has been triggered
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        // The sender lives as long as any Shutdown, so this only stops on the flag changing
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Resolves on ctrl-c, or on SIGTERM where there is such a thing
+async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!(error = %e, "Could not listen for SIGTERM, only ctrl-c will shut down"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!(error = %e, "Could not listen for ctrl-c");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Everything the shutdown sequence has to wind down, in the order it does so
+pub struct ShutdownPlan {
+    pub shutdown: Shutdown,
+    pub health: Arc<Health>,
+    pub cron: JoinHandle<()>,
+    pub storage: Arc<dyn Storage>,
+    pub shard_manager: Arc<Mutex<ShardManager>>,
+    /// How long each step gets before it is given up on
+    pub timeout: Duration,
+}
+
+/// Waits for a termination signal or the client stopping on its own, then stops new commands,
+/// lets running commands and the cron loop's current guild finish, saves one last time and
+/// disconnects from the gateway
+pub async fn run_on_signal(plan: ShutdownPlan, client_stopped: oneshot::Receiver<()>) {
+    tokio::select! {
+        _ = termination_signal() => info!(timeout = ?plan.timeout, "Shutting down"),
+        _ = client_stopped => warn!(timeout = ?plan.timeout, "The client stopped, shutting down"),
+    }
+
+    plan.health.set_shutting_down();
+    plan.shutdown.trigger();
+
+    if timeout(plan.timeout, plan.cron).await.is_err() {
+        warn!("Birthday check did not finish in time, carrying on with shutdown");
+    }
+
+    // Their changes are only marked dirty once they are done, so the save has to come after
+    if timeout(plan.timeout, plan.shutdown.commands_finished())
+        .await
+        .is_err()
+    {
+        warn!("Commands were still running, carrying on with shutdown");
+    }
+
+    match timeout(plan.timeout, plan.storage.flush()).await {
+        Ok(Ok(())) => info!("Saved application state before exiting"),
+        Ok(Err(e)) => error!(error = %e, "Final save failed"),
+        Err(_) => error!("Final save did not finish in time"),
+    }
+
+    // The shard manager's lock is held while it runs, so waiting on it is part of the step too
+    let close_gateway = async { plan.shard_manager.lock().await.shutdown_all().await };
+    if timeout(plan.timeout, close_gateway).await.is_err() {
+        warn!("Gateway did not close in time");
+    }
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Instant};
+
+use poise::BoxFuture;
+use tokio::{fs, sync::Mutex};
+use tracing::{error, warn};
+
+use crate::{
+    config::StorageConfig,
+    migrations::{self, VersionedSave},
+    persistence::{write_atomically, BackupPolicy},
+    recovery,
+    structs::ApplicationState,
+};
+
+use super::GuildWriter;
+
+/// Reads the save file, starting empty when there is none yet. A save that is there but cannot
+/// be read stops startup, unless recovery was asked for.
+pub async fn load(config: &StorageConfig) -> anyhow::Result<ApplicationState> {
+    Ok(load_with_text(config).await?.0)
+}
+
+/// Reads the save file along with the text it was read from, for anything else kept in there
+pub async fn load_with_text(
+    config: &StorageConfig,
+) -> anyhow::Result<(ApplicationState, Option<String>)> {
+    let loaded_bytes = match fs::read(&config.save_location).await {
+        Ok(loaded_bytes) => loaded_bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => anyhow::bail!(
+            "Could not read the save at {}: {}",
+            config.save_location.display(),
+            e
+        ),
+    };
+    let loaded_data = match String::from_utf8(loaded_bytes) {
+        Ok(loaded_data) => loaded_data,
+        // Bytes that are not text are replaced, so recovery can still read around them
+        Err(e) if config.recover => {
+            error!(error = %e, "The save file is not valid text");
+            let text = String::from_utf8_lossy(e.as_bytes()).into_owned();
+            return Ok((recovery::recover(&text, config).await?, None));
+        }
+        Err(e) => anyhow::bail!(
+            "Could not read the save at {}: {}. Nothing was changed. Fix the file, restore a \
+             backup with --restore-backup, or start with --recover to keep what can be read",
+            config.save_location.display(),
+            e
+        ),
+    };
+
+    match migrations::load(&loaded_data, config).await {
+        Ok(state) => Ok((state, Some(loaded_data))),
+        Err(e) if config.recover => {
+            error!(error = %e, "Could not read the save file");
+            Ok((recovery::recover(&loaded_data, config).await?, None))
+        }
+        Err(e) => anyhow::bail!(
+            "Could not read the save at {}: {}. Nothing was changed. Fix the file, restore a \
+             backup with --restore-backup, or start with --recover to keep what can be read",
+            config.save_location.display(),
+            e
+        ),
+    }
+}
+
+/// Rewrites the whole file whichever guilds changed, taking a backup now and again
+pub struct JsonWriter {
+    location: PathBuf,
+    backups: BackupPolicy,
+    state: Arc<ApplicationState>,
+    /// When the last backup was taken
+    last_backup: Mutex<Option<Instant>>,
+}
+
+impl JsonWriter {
+    pub fn new(state: Arc<ApplicationState>, config: &StorageConfig) -> Self {
+        Self {
+            location: config.save_location.clone(),
+            backups: BackupPolicy::new(config),
+            state,
+            last_backup: Mutex::new(None),
+        }
+    }
+
+    /// Writes the whole state, tagged with the mutation log it goes with if there is one
+    pub async fn write_file(&self, log_generation: Option<u64>) -> anyhow::Result<()> {
+        let snapshot = self.state.snapshot().await;
+        let serialized = tokio::task::spawn_blocking(move || {
+            let mut save = VersionedSave::new(&snapshot);
+            if let Some(generation) = log_generation {
+                save = save.with_log_generation(generation);
+            }
+            serde_json::to_vec_pretty(&save)
+        })
+        .await?
+        .map_err(|e| anyhow::anyhow!("Could not serialize application state for saving: {}", e))?;
+
+        write_atomically(&self.location, &serialized)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Could not save application state to {}: {}",
+                    self.location.display(),
+                    e
+                )
+            })?;
+
+        let mut last_backup = self.last_backup.lock().await;
+        if self.backups.is_due(*last_backup) {
+            // The save itself went through, so a failed backup is only worth a warning
+            match self.backups.take(&self.location).await {
+                Ok(()) => *last_backup = Some(Instant::now()),
+                Err(e) => {
+                    warn!(dir = %self.backups.dir.display(), error = %e, "Could not back up application state")
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GuildWriter for JsonWriter {
+    fn write<'a>(&'a self, _dirty: &'a HashSet<u64>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.write_file(None))
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use poise::BoxFuture;
+use serde::Deserialize;
+use tokio::sync::watch::{self, Receiver, Sender};
+use tracing::{debug, error};
+
+use crate::{
+    config::StorageConfig, health::Health, metrics::Metrics, structs::ApplicationState,
+    validation::LoadReport,
+};
+
+pub mod json;
+pub mod sharded;
+pub mod sqlite;
+pub mod wal;
+
+/// How long the saver waits before trying a failed write again, doubled on each failure in a row
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Where the state is persisted. Everything that changes the state marks the guilds it touched
+/// and the storage writes them out in the background.
+pub trait Storage: Send + Sync {
+    /// Queues a guild to be written, cheap enough to call while holding its lock
+    fn mark_dirty(&self, guild_id: u64);
+
+    /// Writes whatever is queued straight away, for when it has to be on disk now
+    fn flush(&self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Called after every confirmed change. Backends that promise each change is on disk before
+    /// it is confirmed write the queue here, the others leave it to the background writer.
+    fn persist(&self) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// One pretty printed JSON file, rewritten in full on every save
+    #[default]
+    Json,
+    /// An SQLite database with a row per guild, only changed guilds are written
+    Sqlite,
+    /// A JSON snapshot plus a log of changed guilds, appended to on each save
+    Wal,
+    /// A directory with a JSON file per guild, only changed guilds are written
+    Sharded,
+}
+
+/// Loads the state from the configured backend and starts writing changes back to it
+pub async fn open(
+    config: &StorageConfig,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+) -> anyhow::Result<(Arc<ApplicationState>, Arc<dyn Storage>)> {
+    match config.backend {
+        Backend::Json => {
+            let state = Arc::new(json::load(config).await?);
+            let writer = json::JsonWriter::new(Arc::clone(&state), config);
+            let storage = QueuedStorage::start(writer, metrics, health);
+            Ok((state, storage))
+        }
+        Backend::Sqlite => {
+            let database = sqlite::Database::open(&config.sqlite_path).await?;
+            let (state, report) = database.load().await?;
+            let state = Arc::new(state);
+            let writer = sqlite::SqliteWriter::new(Arc::clone(&state), database);
+            let storage = QueuedStorage::start(writer, metrics, health);
+            write_back_repairs(storage.as_ref(), &report);
+            Ok((state, storage))
+        }
+        Backend::Sharded => {
+            let (state, report) = sharded::load(config).await?;
+            let state = Arc::new(state);
+            let writer = sharded::ShardedWriter::new(Arc::clone(&state), config.save_dir.clone());
+            let storage = QueuedStorage::start(writer, metrics, health);
+            write_back_repairs(storage.as_ref(), &report);
+            Ok((state, storage))
+        }
+        Backend::Wal => {
+            let (state, writer) = wal::WalWriter::open(config).await?;
+            let storage = QueuedStorage::start(writer, metrics, health);
+            Ok((state, storage))
+        }
+    }
+}
+
+/// Queues the guilds fixed while loading, as backends that write a guild at a time would
+/// otherwise keep the broken copy until something else changes in it
+fn write_back_repairs(storage: &dyn Storage, report: &LoadReport) {
+    for guild_id in report.repaired_guilds() {
+        storage.mark_dirty(guild_id);
+    }
+}
+
+/// A guild ID and its serialized data, no data means the guild is gone
+pub type GuildRow = (u64, Option<String>);
+
+/// Copies the given guilds and serializes them off the async threads
+pub async fn serialize_guilds(
+    state: &ApplicationState,
+    guild_ids: impl IntoIterator<Item = u64>,
+    pretty: bool,
+) -> anyhow::Result<Vec<GuildRow>> {
+    let guilds = state.snapshot_guilds(guild_ids).await;
+    let rows = tokio::task::spawn_blocking(move || {
+        guilds
+            .into_iter()
+            .map(|(guild_id, guild_data)| {
+                let data = guild_data
+                    .map(|guild_data| {
+                        if pretty {
+                            serde_json::to_string_pretty(&guild_data)
+                        } else {
+                            serde_json::to_string(&guild_data)
+                        }
+                    })
+                    .transpose()?;
+                Ok((guild_id, data))
+            })
+            .collect::<serde_json::Result<Vec<_>>>()
+    })
+    .await??;
+    Ok(rows)
+}
+
+/// Does the actual writing for a backend, handed the guilds changed since it last ran
+pub trait GuildWriter: Send + Sync + 'static {
+    fn write<'a>(&'a self, dirty: &'a HashSet<u64>) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Whether every change is written before it is confirmed, rather than batched
+    fn writes_each_change(&self) -> bool {
+        false
+    }
+}
+
+/// Collects dirty guilds and hands them to a writer task, so a burst of changes ends up as
+/// one write
+pub struct QueuedStorage<W> {
+    dirty: Mutex<HashSet<u64>>,
+    wake: Sender<()>,
+    writer: W,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    /// Keeps the writer task and a flush from writing at the same time
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl<W: GuildWriter> QueuedStorage<W> {
+    pub fn start(writer: W, metrics: Arc<Metrics>, health: Arc<Health>) -> Arc<Self> {
+        let (wake, recv) = watch::channel(());
+        let storage = Arc::new(Self {
+            dirty: Mutex::new(HashSet::new()),
+            wake,
+            writer,
+            metrics,
+            health: Arc::clone(&health),
+            write_lock: tokio::sync::Mutex::new(()),
+        });
+        health.spawn_task("saver", saver(recv, Arc::clone(&storage)));
+        storage
+    }
+
+    fn take_dirty(&self) -> HashSet<u64> {
+        std::mem::take(&mut *self.dirty.lock().expect("Dirty guilds lock poisoned"))
+    }
+
+    /// How many guilds are waiting to be written
+    fn pending(&self) -> usize {
+        self.dirty.lock().expect("Dirty guilds lock poisoned").len()
+    }
+
+    /// Times the write and records how it went, the queue is taken once the lock is held
+    async fn write(&self) -> anyhow::Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let dirty = self.take_dirty();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        let started = Instant::now();
+        match self.writer.write(&dirty).await {
+            Ok(()) => {
+                let elapsed = started.elapsed();
+                self.metrics.save_duration.observe(elapsed.as_secs_f64());
+                self.health.record_save();
+                self.metrics.unsaved_guilds.set(self.pending() as i64);
+                debug!(?elapsed, guilds = dirty.len(), "Saved application state");
+                Ok(())
+            }
+            Err(e) => {
+                // Whatever did not make it to disk gets another go on the next write
+                self.dirty
+                    .lock()
+                    .expect("Dirty guilds lock poisoned")
+                    .extend(dirty);
+                self.metrics.save_failures.inc();
+                self.metrics.unsaved_guilds.set(self.pending() as i64);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<W: GuildWriter> Storage for QueuedStorage<W> {
+    fn mark_dirty(&self, guild_id: u64) {
+        self.dirty
+            .lock()
+            .expect("Dirty guilds lock poisoned")
+            .insert(guild_id);
+        let _ = self.wake.send(());
+    }
+
+    fn flush(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(self.write())
+    }
+
+    fn persist(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            if self.writer.writes_each_change() {
+                self.write().await
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+async fn saver<W: GuildWriter>(
+    mut recv: Receiver<()>,
+    storage: Arc<QueuedStorage<W>>,
+) -> anyhow::Result<()> {
+    loop {
+        recv.changed().await?;
+
+        // The guilds of a failed write stay queued, but nothing else may come along to wake the
+        // saver for them, so it keeps trying on its own
+        let mut backoff = RETRY_BACKOFF;
+        while let Err(e) = storage.write().await {
+            error!(error = %e, pending = storage.pending(), retry_in = ?backoff, "Save failed");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+        }
+    }
+}
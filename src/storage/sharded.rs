@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use poise::BoxFuture;
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    config::StorageConfig,
+    migrations,
+    persistence::{quarantine, write_atomically},
+    structs::{ApplicationState, RWGuildData},
+    validation::{read_guild, LoadReport},
+};
+
+use super::{serialize_guilds, GuildWriter};
+
+/// Unreadable guild files are moved in here, inside the save directory
+const QUARANTINE_DIR: &str = "quarantine";
+
+fn guild_file(dir: &Path, guild_id: u64) -> PathBuf {
+    dir.join(format!("{}.json", guild_id))
+}
+
+/// The guild files in the directory, skipping temporary files from an interrupted write and
+/// anything else that is not ours
+async fn guild_files(dir: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let guild_id = path
+            .extension()
+            .filter(|extension| *extension == "json")
+            .and(path.file_stem())
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+        if let Some(guild_id) = guild_id {
+            files.push((guild_id, path));
+        }
+    }
+    Ok(files)
+}
+
+/// Loads every guild file in the save directory. One whose contents cannot be read is moved
+/// aside and skipped, so the other guilds still load, while failing to read a file at all stops
+/// the load as the file itself may be fine. An empty directory next to an existing JSON save
+/// is refused, as starting empty would leave every guild in the save behind.
+pub async fn load(config: &StorageConfig) -> anyhow::Result<(ApplicationState, LoadReport)> {
+    let dir = config.save_dir.as_path();
+    fs::create_dir_all(dir).await?;
+
+    let files = guild_files(dir).await?;
+    if files.is_empty() && fs::try_exists(&config.save_location).await? {
+        anyhow::bail!(
+            "{} holds no guilds but there is a JSON save at {}. Copy it over with `origin-bot migrate-to-sharded`, or move it away to start empty",
+            dir.display(),
+            config.save_location.display()
+        );
+    }
+
+    let mut guild_map = HashMap::new();
+    let mut quarantined = 0;
+    let mut report = LoadReport::default();
+    for (guild_id, path) in files {
+        let bytes = fs::read(&path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Could not read {}: {}", path.display(), e))?;
+        let read = serde_json::from_slice(&bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|value| {
+                read_guild(&guild_id.to_string(), value, &mut report)
+                    .ok_or_else(|| "The guild could not be read".to_string())
+            });
+        match read {
+            Ok(guild_data) => {
+                guild_map.insert(guild_id, Arc::new(RWGuildData::from(guild_data)));
+            }
+            Err(error) => {
+                quarantined += 1;
+                match quarantine(&path, &dir.join(QUARANTINE_DIR)).await {
+                    Ok(moved) => warn!(
+                        guild_id,
+                        error,
+                        quarantined = %moved.display(),
+                        "Could not read a guild's save, it was moved aside and the guild starts empty"
+                    ),
+                    Err(e) => warn!(
+                        guild_id,
+                        error,
+                        move_error = %e,
+                        "Could not read a guild's save and could not move it aside either"
+                    ),
+                }
+            }
+        }
+    }
+
+    report.log();
+    info!(
+        guilds = guild_map.len(),
+        quarantined, "Loaded guilds from the save directory"
+    );
+    let state = ApplicationState {
+        guild_map: guild_map.into(),
+    };
+    Ok((state, report))
+}
+
+/// Copies the JSON save into a file per guild, returning how many guilds were copied. A
+/// directory that already has guilds in it is only replaced when asked to.
+pub async fn import_json(
+    config: &StorageConfig,
+    target: Option<PathBuf>,
+    overwrite: bool,
+) -> anyhow::Result<(usize, PathBuf)> {
+    let target = target.unwrap_or_else(|| config.save_dir.clone());
+
+    let text = fs::read_to_string(&config.save_location)
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not read {}: {}", config.save_location.display(), e))?;
+    let state = Arc::new(migrations::load(&text, config).await?);
+
+    fs::create_dir_all(&target).await?;
+    let existing = guild_files(&target).await?;
+    if !existing.is_empty() {
+        if !overwrite {
+            anyhow::bail!(
+                "{} already holds {} guilds, pass --overwrite to replace them",
+                target.display(),
+                existing.len()
+            );
+        }
+        for (_, path) in existing {
+            fs::remove_file(path).await?;
+        }
+    }
+
+    let guild_ids = state
+        .guild_map
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect::<HashSet<_>>();
+    let writer = ShardedWriter::new(state, target.clone());
+    writer.write_dirty(&guild_ids).await?;
+    Ok((guild_ids.len(), target))
+}
+
+/// Writes each dirty guild to its own file and removes the files of guilds that are gone
+pub struct ShardedWriter {
+    dir: PathBuf,
+    state: Arc<ApplicationState>,
+}
+
+impl ShardedWriter {
+    pub fn new(state: Arc<ApplicationState>, dir: PathBuf) -> Self {
+        Self { dir, state }
+    }
+
+    async fn write_dirty(&self, dirty: &HashSet<u64>) -> anyhow::Result<()> {
+        let mut failed = 0;
+        for (guild_id, data) in serialize_guilds(&self.state, dirty.iter().copied(), true).await? {
+            let path = guild_file(&self.dir, guild_id);
+            let res = match data {
+                Some(data) => write_atomically(&path, data.as_bytes()).await,
+                None => match fs::remove_file(&path).await {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                    res => res,
+                },
+            };
+            if let Err(e) = res {
+                failed += 1;
+                warn!(guild_id, error = %e, "Could not save guild");
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("{} of {} guilds could not be saved", failed, dirty.len());
+        }
+        Ok(())
+    }
+}
+
+impl GuildWriter for ShardedWriter {
+    fn write<'a>(&'a self, dirty: &'a HashSet<u64>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.write_dirty(dirty))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAVE: &str = include_str!("../../tests/fixtures/save-v1.json");
+
+    #[tokio::test]
+    async fn an_existing_json_save_has_to_be_migrated_first() {
+        let dir = std::env::temp_dir().join(format!("origin-bot-sharded-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = StorageConfig {
+            save_location: dir.join("birthdays.json"),
+            save_dir: dir.join("guilds"),
+            ..Default::default()
+        };
+        std::fs::write(&config.save_location, SAVE).unwrap();
+
+        assert!(load(&config).await.is_err());
+
+        let (copied, target) = import_json(&config, None, false).await.unwrap();
+        assert_eq!(copied, 1);
+        assert_eq!(target, config.save_dir);
+        let (state, _) = load(&config).await.unwrap();
+        assert!(state.guild_map.read().await.contains_key(&100));
+
+        assert!(import_json(&config, None, false).await.is_err());
+        assert!(import_json(&config, None, true).await.is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_broken_guild_file_is_moved_aside_and_the_rest_load() {
+        let dir =
+            std::env::temp_dir().join(format!("origin-bot-sharded-broken-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = StorageConfig {
+            save_location: dir.join("birthdays.json"),
+            save_dir: dir.join("guilds"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&config.save_dir).unwrap();
+        std::fs::write(guild_file(&config.save_dir, 1), "{}").unwrap();
+        std::fs::write(guild_file(&config.save_dir, 2), "{\"birthday_sch").unwrap();
+
+        let (state, _) = load(&config).await.unwrap();
+        let guild_ids = state
+            .guild_map
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(guild_ids, [1]);
+        assert!(!guild_file(&config.save_dir, 2).exists());
+        let quarantined = std::fs::read_dir(config.save_dir.join(QUARANTINE_DIR))
+            .unwrap()
+            .count();
+        assert_eq!(quarantined, 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
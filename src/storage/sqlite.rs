@@ -0,0 +1,302 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use poise::BoxFuture;
+use rusqlite::{params, Connection};
+use tokio::fs;
+
+use crate::{
+    config::StorageConfig,
+    migrations,
+    structs::{ApplicationState, RWGuildData},
+    validation::{read_guild, LoadReport},
+};
+
+use super::{serialize_guilds, GuildRow, GuildWriter};
+
+/// Bumped together with a migration in `Database::open` whenever the tables change
+const SCHEMA_VERSION: i64 = 1;
+
+/// An SQLite database holding each guild as a row of JSON, so a change to one guild only
+/// rewrites that row
+#[derive(Clone)]
+pub struct Database {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    pub async fn open(path: &Path) -> anyhow::Result<Self> {
+        let path = path.to_path_buf();
+        let connection = tokio::task::spawn_blocking(move || open_connection(&path)).await??;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs blocking database work off the async threads
+    async fn run<T, F>(&self, work: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        let res = tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().expect("Database lock poisoned");
+            work(&mut connection)
+        })
+        .await??;
+        Ok(res)
+    }
+
+    /// Reads every guild, along with what had to be repaired or dropped on the way. A row that is
+    /// not JSON at all is left in the database and skipped.
+    pub async fn load(&self) -> anyhow::Result<(ApplicationState, LoadReport)> {
+        let rows = self
+            .run(|connection| {
+                let mut statement = connection.prepare("SELECT guild_id, data FROM guilds")?;
+                let rows = statement
+                    .query_map([], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(rows)
+            })
+            .await?;
+
+        let mut guild_map = HashMap::with_capacity(rows.len());
+        let mut report = LoadReport::default();
+        for (guild_id, data) in rows {
+            let guild = guild_id.to_string();
+            let value = match serde_json::from_str(&data) {
+                Ok(value) => value,
+                Err(e) => {
+                    report.dropped(&guild, None, format!("Not valid JSON: {}", e));
+                    continue;
+                }
+            };
+            if let Some(guild_data) = read_guild(&guild, value, &mut report) {
+                guild_map.insert(guild_id as u64, Arc::new(RWGuildData::from(guild_data)));
+            }
+        }
+        report.log();
+        let state = ApplicationState {
+            guild_map: guild_map.into(),
+        };
+        Ok((state, report))
+    }
+
+    pub async fn guild_count(&self) -> anyhow::Result<usize> {
+        self.run(|connection| {
+            connection.query_row("SELECT COUNT(*) FROM guilds", [], |row| {
+                row.get::<_, i64>(0)
+            })
+        })
+        .await
+        .map(|count| count as usize)
+    }
+
+    /// Upserts and deletes the given guilds in one transaction
+    async fn write_guilds(&self, rows: Vec<GuildRow>, replace_all: bool) -> anyhow::Result<()> {
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            if replace_all {
+                transaction.execute("DELETE FROM guilds", [])?;
+            }
+            {
+                let mut upsert = transaction.prepare(
+                    "INSERT INTO guilds (guild_id, data) VALUES (?1, ?2)
+                     ON CONFLICT (guild_id) DO UPDATE SET data = excluded.data",
+                )?;
+                let mut delete = transaction.prepare("DELETE FROM guilds WHERE guild_id = ?1")?;
+                for (guild_id, data) in rows {
+                    // Discord IDs stay well below 2^63, so they fit in SQLite's signed integers
+                    match data {
+                        Some(data) => upsert.execute(params![guild_id as i64, data])?,
+                        None => delete.execute(params![guild_id as i64])?,
+                    };
+                }
+            }
+            transaction.commit()
+        })
+        .await
+    }
+}
+
+fn open_connection(path: &Path) -> anyhow::Result<Connection> {
+    let connection = Connection::open(path)?;
+    // WAL keeps readers and the writer out of each other's way, FULL makes each commit durable
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "FULL")?;
+
+    let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        anyhow::bail!(
+            "{} is schema version {}, newer than the {} this build understands",
+            path.display(),
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    if version < 1 {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS guilds (
+                guild_id INTEGER PRIMARY KEY,
+                data TEXT NOT NULL
+            )",
+        )?;
+    }
+    connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    Ok(connection)
+}
+
+/// Writes only the guilds that changed
+pub struct SqliteWriter {
+    state: Arc<ApplicationState>,
+    database: Database,
+}
+
+impl SqliteWriter {
+    pub fn new(state: Arc<ApplicationState>, database: Database) -> Self {
+        Self { state, database }
+    }
+
+    async fn write_dirty(&self, dirty: &HashSet<u64>) -> anyhow::Result<()> {
+        let rows = serialize_guilds(&self.state, dirty.iter().copied(), false).await?;
+        self.database.write_guilds(rows, false).await
+    }
+}
+
+impl GuildWriter for SqliteWriter {
+    fn write<'a>(&'a self, dirty: &'a HashSet<u64>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.write_dirty(dirty))
+    }
+}
+
+/// Copies the JSON save into the SQLite database, returning how many guilds were copied.
+/// A database that already has guilds in it is only replaced when asked to.
+pub async fn import_json(
+    config: &StorageConfig,
+    target: Option<PathBuf>,
+    overwrite: bool,
+) -> anyhow::Result<(usize, PathBuf)> {
+    let target = target.unwrap_or_else(|| config.sqlite_path.clone());
+
+    let text = fs::read_to_string(&config.save_location)
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not read {}: {}", config.save_location.display(), e))?;
+    let state = migrations::load(&text, config).await?;
+
+    let database = Database::open(&target).await?;
+    let existing = database.guild_count().await?;
+    if existing > 0 && !overwrite {
+        anyhow::bail!(
+            "{} already holds {} guilds, pass --overwrite to replace them",
+            target.display(),
+            existing
+        );
+    }
+
+    let guild_ids = state
+        .guild_map
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    let rows = serialize_guilds(&state, guild_ids, false).await?;
+    let copied = rows.len();
+    database.write_guilds(rows, true).await?;
+    Ok((copied, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAVE: &str = include_str!("../../tests/fixtures/save-v1.json");
+
+    async fn guilds_as_json(state: &ApplicationState) -> HashMap<u64, serde_json::Value> {
+        let mut guilds = HashMap::new();
+        for (guild_id, guild_data) in state.guild_map.read().await.iter() {
+            let guild_data = guild_data.rw_lock.read().await;
+            guilds.insert(*guild_id, serde_json::to_value(&*guild_data).unwrap());
+        }
+        guilds
+    }
+
+    #[tokio::test]
+    async fn a_json_save_is_imported_whole() {
+        let dir = std::env::temp_dir().join(format!("origin-bot-sqlite-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = StorageConfig {
+            save_location: dir.join("birthdays.json"),
+            sqlite_path: dir.join("birthdays.sqlite3"),
+            ..Default::default()
+        };
+        std::fs::write(&config.save_location, SAVE).unwrap();
+
+        let (copied, target) = import_json(&config, None, false).await.unwrap();
+        assert_eq!(copied, 1);
+        assert_eq!(target, config.sqlite_path);
+
+        let (imported, report) = Database::open(&target).await.unwrap().load().await.unwrap();
+        assert!(report.is_empty());
+        let saved = migrations::load(SAVE, &config).await.unwrap();
+        assert_eq!(
+            guilds_as_json(&imported).await,
+            guilds_as_json(&saved).await
+        );
+
+        // A second import would replace what is there, so it has to be asked for
+        assert!(import_json(&config, None, false).await.is_err());
+        assert!(import_json(&config, None, true).await.is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_row_that_is_not_json_is_reported_and_skipped() {
+        let path = std::env::temp_dir().join(format!(
+            "origin-bot-sqlite-bad-row-{}.sqlite3",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let database = Database::open(&path).await.unwrap();
+        let guild: serde_json::Value = serde_json::from_str(SAVE).unwrap();
+        let mut repairable = guild["guild_map"]["100"]["rw_lock"].clone();
+        repairable["timezone"] = serde_json::json!("Mars/Olympus_Mons");
+        database
+            .write_guilds(
+                vec![
+                    (1, Some(guild["guild_map"]["100"]["rw_lock"].to_string())),
+                    (2, Some("{\"schedule\": [".to_string())),
+                    (3, Some(repairable.to_string())),
+                ],
+                true,
+            )
+            .await
+            .unwrap();
+
+        let (state, report) = database.load().await.unwrap();
+        let mut guild_ids = state
+            .guild_map
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        guild_ids.sort_unstable();
+        assert_eq!(guild_ids, [1, 3]);
+        assert_eq!(report.problems.len(), 2);
+        assert_eq!(report.repaired_guilds(), HashSet::from([3]));
+
+        // Left where it is for someone to look at
+        assert_eq!(database.guild_count().await.unwrap(), 3);
+        drop(database);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use poise::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::StorageConfig,
+    persistence::write_atomically,
+    structs::{ApplicationState, RWGuildData},
+    validation::{read_guild, LoadReport},
+};
+
+use super::{json, GuildWriter};
+
+/// First line of every log, ties it to the snapshot it carries on from
+#[derive(Serialize, Deserialize)]
+struct LogHeader {
+    generation: u64,
+}
+
+/// A guild as it was right after a change, no data means it was removed. Replaying only ever
+/// needs the last record for each guild.
+#[derive(Serialize, Deserialize)]
+struct LogRecord<T> {
+    guild_id: u64,
+    at: DateTime<Utc>,
+    data: Option<T>,
+}
+
+/// The part of a snapshot saying which log follows it
+#[derive(Deserialize)]
+struct SnapshotMarker {
+    #[serde(default)]
+    log_generation: u64,
+}
+
+struct LogFile {
+    file: File,
+    generation: u64,
+    records: usize,
+    started: Instant,
+    /// Set when a snapshot was written but the log that follows it could not be started, so
+    /// appending to this one would be lost on replay
+    stale: bool,
+}
+
+/// Appends each change to a log, fsynced before the change is confirmed, and folds the log
+/// into a JSON snapshot every so often. A record holds the whole guild as it was after the
+/// change, so replaying never depends on an earlier record.
+///
+/// A snapshot and a log share a generation. Compaction writes a snapshot for the next
+/// generation before starting its log, so a crash in between leaves a snapshot that already
+/// holds everything in the old log, which is then skipped.
+pub struct WalWriter {
+    snapshot: json::JsonWriter,
+    state: Arc<ApplicationState>,
+    log_path: PathBuf,
+    log: Mutex<LogFile>,
+    compact_after: usize,
+    compact_interval: Duration,
+}
+
+impl WalWriter {
+    /// Loads the snapshot, replays the log on top of it and compacts the lot into a new
+    /// generation
+    pub async fn open(config: &StorageConfig) -> anyhow::Result<(Arc<ApplicationState>, Self)> {
+        let (mut state, text) = json::load_with_text(config).await?;
+        let generation = text
+            .and_then(|text| serde_json::from_str::<SnapshotMarker>(&text).ok())
+            .map_or(0, |marker| marker.log_generation);
+
+        let log_path = config.wal_path();
+        let replayed = replay(&mut state, &log_path, generation).await?;
+        if replayed > 0 {
+            info!(replayed, "Replayed the mutation log");
+        }
+
+        let state = Arc::new(state);
+        let snapshot = json::JsonWriter::new(Arc::clone(&state), config);
+        snapshot.write_file(Some(generation + 1)).await?;
+        let log = start_log(&log_path, generation + 1).await?;
+
+        let writer = Self {
+            snapshot,
+            state: Arc::clone(&state),
+            log_path,
+            log: Mutex::new(log),
+            compact_after: config.wal_compact_after,
+            compact_interval: config.wal_compact_interval(),
+        };
+        Ok((state, writer))
+    }
+
+    async fn compact(&self, log: &mut LogFile) -> anyhow::Result<()> {
+        let generation = log.generation + 1;
+        self.snapshot.write_file(Some(generation)).await?;
+        log.stale = true;
+        *log = start_log(&self.log_path, generation).await?;
+        debug!(generation, "Compacted the mutation log");
+        Ok(())
+    }
+
+    async fn append(&self, dirty: &HashSet<u64>) -> anyhow::Result<()> {
+        let mut log = self.log.lock().await;
+        if log.stale {
+            // The snapshot this writes holds the dirty guilds too
+            return self.compact(&mut log).await;
+        }
+
+        let at = Utc::now();
+        let guilds = self.state.snapshot_guilds(dirty.iter().copied()).await;
+        let lines = tokio::task::spawn_blocking(move || {
+            let mut lines = String::new();
+            for (guild_id, data) in guilds {
+                lines.push_str(&serde_json::to_string(&LogRecord { guild_id, at, data })?);
+                lines.push('\n');
+            }
+            serde_json::Result::Ok(lines)
+        })
+        .await??;
+
+        log.file.write_all(lines.as_bytes()).await?;
+        log.file.sync_data().await?;
+        log.records += dirty.len();
+
+        if log.records >= self.compact_after || log.started.elapsed() >= self.compact_interval {
+            // The records are safely in the log already, so this can wait for the next write
+            if let Err(e) = self.compact(&mut log).await {
+                warn!(error = %e, "Could not compact the mutation log");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GuildWriter for WalWriter {
+    fn write<'a>(&'a self, dirty: &'a HashSet<u64>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.append(dirty))
+    }
+
+    fn writes_each_change(&self) -> bool {
+        true
+    }
+}
+
+/// Replaces the log with an empty one for the given generation, opened for appending
+async fn start_log(path: &Path, generation: u64) -> anyhow::Result<LogFile> {
+    let mut header = serde_json::to_string(&LogHeader { generation })?;
+    header.push('\n');
+    write_atomically(path, header.as_bytes()).await?;
+
+    let file = OpenOptions::new().append(true).open(path).await?;
+    Ok(LogFile {
+        file,
+        generation,
+        records: 0,
+        started: Instant::now(),
+        stale: false,
+    })
+}
+
+/// Applies the log to the snapshot it follows, returning how many records were replayed. Only
+/// the last record can be cut off by a crash, so that one is skipped. A broken record anywhere
+/// else fails the load, as compacting would lose every record after it.
+async fn replay(
+    state: &mut ApplicationState,
+    path: &Path,
+    generation: u64,
+) -> anyhow::Result<usize> {
+    let text = match fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut lines = text.lines();
+    let header: LogHeader = match lines.next() {
+        Some(line) => serde_json::from_str(line)
+            .map_err(|e| anyhow::anyhow!("Could not read {}: {}", path.display(), e))?,
+        None => return Ok(0),
+    };
+    if header.generation != generation {
+        info!(
+            log = header.generation,
+            snapshot = generation,
+            "The mutation log is already in the snapshot, skipping it"
+        );
+        return Ok(0);
+    }
+
+    let guild_map = state.guild_map.get_mut();
+    let mut replayed = 0;
+    let mut report = LoadReport::default();
+    // Every record is written with its newline, a crash part way through leaves it without
+    let torn_end = !text.ends_with('\n');
+    let mut lines = lines.enumerate().peekable();
+    while let Some((number, line)) = lines.next() {
+        let record: LogRecord<Value> = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) if torn_end && lines.peek().is_none() => {
+                warn!(
+                    line = number + 2,
+                    error = %e,
+                    "The mutation log ends in a record cut off part way, ignoring it"
+                );
+                break;
+            }
+            Err(e) => anyhow::bail!(
+                "{} has a broken record on line {}: {}. Move the log aside to start from the snapshot without it",
+                path.display(),
+                number + 2,
+                e
+            ),
+        };
+        match record.data {
+            // A guild that cannot be read at all keeps whatever it had before this record
+            Some(data) => {
+                if let Some(guild_data) =
+                    read_guild(&record.guild_id.to_string(), data, &mut report)
+                {
+                    guild_map.insert(record.guild_id, Arc::new(RWGuildData::from(guild_data)));
+                }
+            }
+            None => {
+                guild_map.remove(&record.guild_id);
+            }
+        }
+        replayed += 1;
+    }
+    report.log();
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: u64 = 100;
+
+    fn record(guild_id: u64) -> String {
+        serde_json::to_string(&LogRecord {
+            guild_id,
+            at: Utc::now(),
+            data: Some(serde_json::json!({
+                "announcement_channel": 200,
+                "schedule": [],
+                "birthday_map": {},
+            })),
+        })
+        .unwrap()
+    }
+
+    async fn replay_text(name: &str, text: &str) -> anyhow::Result<(usize, ApplicationState)> {
+        let path =
+            std::env::temp_dir().join(format!("origin-bot-wal-{}-{}", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let mut state = ApplicationState::default();
+        let replayed = replay(&mut state, &path, 1).await;
+        std::fs::remove_file(&path).unwrap();
+        replayed.map(|replayed| (replayed, state))
+    }
+
+    #[tokio::test]
+    async fn a_record_cut_off_at_the_end_is_skipped() {
+        let header = r#"{"generation":1}"#;
+        let torn = &record(GUILD + 1)[..20];
+        let text = format!("{}\n{}\n{}", header, record(GUILD), torn);
+
+        let (replayed, state) = replay_text("torn", &text).await.unwrap();
+        assert_eq!(replayed, 1);
+        assert!(state.guild_map.read().await.contains_key(&GUILD));
+    }
+
+    #[tokio::test]
+    async fn a_broken_record_before_the_end_fails_the_replay() {
+        let header = r#"{"generation":1}"#;
+        let text = format!("{}\nnot a record\n{}\n", header, record(GUILD));
+        assert!(replay_text("broken", &text).await.is_err());
+
+        // A whole last line that does not parse was not cut off by a crash either
+        let text = format!("{}\n{}\nnot a record\n", header, record(GUILD));
+        assert!(replay_text("broken-last", &text).await.is_err());
+    }
+}
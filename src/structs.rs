@@ -1,88 +1,123 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
 use chrono_tz::Tz;
+use chronoutil::delta;
 use serde::{de, Deserialize, Deserializer, Serialize};
 use std::{
-    collections::{BTreeSet, HashMap},
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
     sync::Arc,
 };
 use tokio::sync::RwLock;
 
-use crate::persistence::SaveManager;
+use crate::{
+    config::Templates, health::Health, metrics::Metrics, names::NameResolver, shutdown::Shutdown,
+    storage::Storage,
+};
 
 pub struct Data {
     pub state: Arc<ApplicationState>,
-    pub saver: Arc<SaveManager>,
+    pub storage: Arc<dyn Storage>,
+    pub names: Arc<NameResolver>,
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<Health>,
+    pub templates: Arc<Templates>,
+    pub shutdown: Shutdown,
+    pub feed_base_url: Option<String>,
+    /// Whether member departures arrive at all, see DiscordConfig::track_members
+    pub track_members: bool,
 } // User data, which is stored and accessible in all command invocations'
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Debug)]
 pub struct ApplicationState {
-    #[serde(with = "rw_lock_app_state")]
-    pub guild_map: RwLock<HashMap<u64, RWGuildData>>,
+    /// Guilds are shared so they can be worked on without holding the map's lock
+    pub guild_map: RwLock<HashMap<u64, Arc<RWGuildData>>>,
 }
 
-mod rw_lock_app_state {
-    use std::collections::HashMap;
-
-    use serde::de::Deserializer;
-    use serde::ser::Serializer;
-    use serde::{Deserialize, Serialize};
-    use tokio::sync::RwLock;
-
-    use super::RWGuildData;
+impl ApplicationState {
+    /// Copies every guild for saving. The map is let go before any guild is locked, and each
+    /// guild is only locked for as long as its copy takes, which is cheap as the birthdays
+    /// themselves are shared.
+    pub async fn snapshot(&self) -> StateSnapshot {
+        let guilds: Vec<(u64, Arc<RWGuildData>)> = self
+            .guild_map
+            .read()
+            .await
+            .iter()
+            .map(|(guild_id, guild_data)| (*guild_id, Arc::clone(guild_data)))
+            .collect();
+        let mut guild_map = HashMap::with_capacity(guilds.len());
+        for (guild_id, guild_data) in guilds {
+            let rw_lock = guild_data.rw_lock.read().await.clone();
+            guild_map.insert(guild_id, GuildSnapshot { rw_lock });
+        }
+        StateSnapshot { guild_map }
+    }
 
-    pub fn serialize<S>(val: &RwLock<HashMap<u64, RWGuildData>>, s: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        tokio::task::block_in_place(|| {
-            let inner = val.blocking_read();
-            HashMap::<u64, RWGuildData>::serialize(&inner, s)
-        })
+    /// Copies the given guilds for saving, None for any guild that no longer exists
+    pub async fn snapshot_guilds(
+        &self,
+        guild_ids: impl IntoIterator<Item = u64>,
+    ) -> Vec<(u64, Option<GuildData>)> {
+        let guilds: Vec<(u64, Option<Arc<RWGuildData>>)> = {
+            let reader = self.guild_map.read().await;
+            guild_ids
+                .into_iter()
+                .map(|guild_id| (guild_id, reader.get(&guild_id).cloned()))
+                .collect()
+        };
+        let mut copies = Vec::with_capacity(guilds.len());
+        for (guild_id, guild_data) in guilds {
+            let guild_data = match guild_data {
+                Some(guild_data) => Some(guild_data.rw_lock.read().await.clone()),
+                None => None,
+            };
+            copies.push((guild_id, guild_data));
+        }
+        copies
     }
 
-    pub fn deserialize<'de, D>(de: D) -> Result<RwLock<HashMap<u64, RWGuildData>>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        tokio::task::block_in_place(|| {
-            let res: HashMap<u64, RWGuildData> = Deserialize::deserialize(de)?;
-            Ok(RwLock::new(res))
-        })
+    /// The default timezone of a guild, None if it has none or is not known
+    pub async fn guild_timezone(&self, guild_id: u64) -> Option<Tz> {
+        let guild_data = self.guild_map.read().await.get(&guild_id).cloned()?;
+        let timezone = guild_data.rw_lock.read().await.timezone;
+        timezone
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct RWGuildData {
-    #[serde(with = "rw_lock_guild_data")]
-    pub rw_lock: RwLock<GuildData>,
+impl From<StateSnapshot> for ApplicationState {
+    fn from(snapshot: StateSnapshot) -> Self {
+        let guild_map = snapshot
+            .guild_map
+            .into_iter()
+            .map(|(guild_id, guild)| (guild_id, Arc::new(RWGuildData::from(guild.rw_lock))))
+            .collect();
+        Self {
+            guild_map: RwLock::new(guild_map),
+        }
+    }
 }
 
-mod rw_lock_guild_data {
-    use serde::de::Deserializer;
-    use serde::ser::Serializer;
-    use serde::{Deserialize, Serialize};
-    use tokio::sync::RwLock;
+/// A lock free copy of the state, laid out the same as the save file
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct StateSnapshot {
+    pub guild_map: HashMap<u64, GuildSnapshot>,
+}
 
-    use super::GuildData;
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GuildSnapshot {
+    pub rw_lock: GuildData,
+}
 
-    pub fn serialize<S>(val: &RwLock<GuildData>, s: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        tokio::task::block_in_place(|| {
-            let inner = val.blocking_read();
-            GuildData::serialize(&inner, s)
-        })
-    }
+#[derive(Default, Debug)]
+pub struct RWGuildData {
+    pub rw_lock: RwLock<GuildData>,
+}
 
-    pub fn deserialize<'de, D>(de: D) -> Result<RwLock<GuildData>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        tokio::task::block_in_place(|| {
-            let res: GuildData = Deserialize::deserialize(de)?;
-            Ok(RwLock::new(res))
-        })
+impl From<GuildData> for RWGuildData {
+    fn from(guild_data: GuildData) -> Self {
+        Self {
+            rw_lock: RwLock::new(guild_data),
+        }
     }
 }
 
@@ -94,11 +129,136 @@ pub struct GuildData {
     pub announcement_channel: Option<u64>,
     #[serde(flatten)]
     pub birthday_schedule: BirthdaySchedule,
+    #[serde(default)]
+    pub trash: BirthdayTrash,
+    /// Secret part of the calendar and RSS feed URLs, feeds are off while it is unset
+    #[serde(default)]
+    pub feed_token: Option<String>,
+    /// What happens to birthdays when their member leaves or the bot is removed from the guild
+    #[serde(default)]
+    pub departure_policy: DeparturePolicy,
+    /// Set while the bot is not in the guild, nothing is announced until it is added back
+    #[serde(default)]
+    pub removed_at: Option<DateTime<Utc>>,
+    /// Birthdays of members who left under the archive policy, put back when they rejoin
+    #[serde(default)]
+    pub departed: HashMap<u64, Arc<BirthdayInfo>>,
+    /// Members who left under the pause policy, their birthdays go unannounced until they rejoin
+    #[serde(default)]
+    pub absent: HashSet<u64>,
+    /// Why birthdays cannot go to the announcement channel, set until the channel works again
+    #[serde(default)]
+    pub misconfigured: Option<String>,
+}
+
+/// What a guild does with the birthdays of members who leave. Archive and pause both keep a
+/// guild's data while the bot is removed, delete drops it.
+#[derive(
+    poise::ChoiceParameter, Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DeparturePolicy {
+    #[name = "Archive: take the birthday off the schedule until they rejoin"]
+    Archive,
+    #[default]
+    #[name = "Pause: keep the birthday listed but do not announce it until they rejoin"]
+    Pause,
+    #[name = "Delete: move the birthday to the trash, restorable if they rejoin in time"]
+    Delete,
+}
+
+impl GuildData {
+    /// Removes a birthday from the schedule and moves it into the trash
+    pub fn trash_birthday(&mut self, user_id: u64, deleted_by: u64) -> Option<Arc<BirthdayInfo>> {
+        let removed = self.birthday_schedule.remove(&user_id)?;
+        self.trash.push(TrashedBirthday {
+            info: Arc::clone(&removed),
+            deleted_at: Utc::now(),
+            deleted_by,
+            departed: false,
+        });
+        Some(removed)
+    }
+
+    /// Birthdays starting within the window, with their local date and how many days away it is
+    pub fn upcoming(
+        &self,
+        now: DateTime<Utc>,
+        window: Duration,
+    ) -> impl Iterator<Item = (Arc<BirthdayInfo>, NaiveDate, i64)> + '_ {
+        let window_end = now + window;
+        self.birthday_schedule
+            .ordered_iter()
+            .take_while(move |info| info.datetime < window_end)
+            .filter_map(move |info| {
+                let local_date = info.local_date(self.timezone);
+                let days_until = (local_date - info.local_today(self.timezone, now)).num_days();
+                // Anything already behind us is about to be rescheduled by the cron loop
+                if days_until < 0 {
+                    return None;
+                }
+                Some((Arc::clone(info), local_date, days_until))
+            })
+    }
+
+    /// Applies the departure policy to a member who left, returns whether anything changed
+    pub fn member_left(&mut self, user_id: u64) -> bool {
+        match self.departure_policy {
+            DeparturePolicy::Archive => match self.birthday_schedule.remove(&user_id) {
+                Some(info) => {
+                    self.departed.insert(user_id, info);
+                    true
+                }
+                None => false,
+            },
+            DeparturePolicy::Pause => {
+                self.birthday_schedule.get(user_id).is_some() && self.absent.insert(user_id)
+            }
+            DeparturePolicy::Delete => match self.birthday_schedule.remove(&user_id) {
+                Some(info) => {
+                    self.trash.push(TrashedBirthday {
+                        info,
+                        deleted_at: Utc::now(),
+                        deleted_by: user_id,
+                        departed: true,
+                    });
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// Undoes whatever happened to a member's birthday when they left, returns whether anything
+    /// changed. A birthday set again while they were away wins over the one they left with.
+    pub fn member_returned(&mut self, user_id: u64) -> bool {
+        let mut changed = self.absent.remove(&user_id);
+        if let Some(info) = self.departed.remove(&user_id) {
+            changed = true;
+            if self.birthday_schedule.get(user_id).is_none() {
+                let _ = self.birthday_schedule.insert(caught_up(info, Utc::now()));
+            }
+        }
+        if self.birthday_schedule.get(user_id).is_none() {
+            if let Some(trashed) = self.trash.take_departed(user_id) {
+                self.restore_birthday(trashed);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Puts a trashed birthday back on the schedule
+    pub fn restore_birthday(&mut self, trashed: TrashedBirthday) -> Arc<BirthdayInfo> {
+        let restored = trashed.into_restored(Utc::now());
+        let _ = self.birthday_schedule.insert(Arc::clone(&restored));
+        restored
+    }
 }
 
 mod opt_tz_serde {
     use chrono_tz::Tz;
-    use serde::de::Deserializer;
+    use serde::de::{self, Deserializer};
     use serde::ser::Serializer;
     use serde::{Deserialize, Serialize};
     use std::str::FromStr;
@@ -116,9 +276,7 @@ mod opt_tz_serde {
     {
         let res: Option<String> = Deserialize::deserialize(de)?;
         match res {
-            Some(inner) => Ok(Some(
-                Tz::from_str(&inner).expect("Invalid timezone to deserialize"),
-            )),
+            Some(inner) => Tz::from_str(&inner).map(Some).map_err(de::Error::custom),
             None => Ok(None),
         }
     }
@@ -139,6 +297,7 @@ where
     let string_map = <HashMap<String, Arc<BirthdayInfo>>>::deserialize(de)?;
     let mut map = HashMap::with_capacity(string_map.len());
     for (s, v) in string_map {
+        // Saves are checked before this, where a bad key only costs its own entry
         let k = s.parse::<u64>().map_err(de::Error::custom)?;
         map.insert(k, v);
     }
@@ -183,27 +342,11 @@ impl BirthdaySchedule {
     }
 
     pub fn peek_occured(&self) -> Vec<&Arc<BirthdayInfo>> {
-        let mut res = vec![];
-        let mut continue_checking = true;
         let start_time = Utc::now();
-
-        while continue_checking {
-            match self.peek_first() {
-                Some(inner) => {
-                    if inner.datetime < start_time {
-                        res.push(inner);
-                    } else {
-                        continue_checking = false;
-                        continue;
-                    }
-                }
-                None => {
-                    continue_checking = false;
-                    continue;
-                }
-            }
-        }
-        res
+        self.schedule
+            .iter()
+            .take_while(|inner| inner.datetime < start_time)
+            .collect()
     }
 
     pub fn pop_occured(&mut self) -> Vec<Arc<BirthdayInfo>> {
@@ -213,23 +356,16 @@ impl BirthdaySchedule {
 
         while continue_checking {
             match self.peek_first() {
-                Some(inner) => {
-                    if inner.datetime < start_time {
-                        match self.pop_first() {
-                            Some(inner) => {
-                                res.push(inner);
-                            }
-                            None => {
-                                continue_checking = false;
-                                continue;
-                            }
-                        }
-                    } else {
+                Some(inner) if inner.datetime < start_time => match self.pop_first() {
+                    Some(inner) => {
+                        res.push(inner);
+                    }
+                    None => {
                         continue_checking = false;
                         continue;
                     }
-                }
-                None => {
+                },
+                _ => {
                     continue_checking = false;
                     continue;
                 }
@@ -249,13 +385,415 @@ impl BirthdaySchedule {
     pub fn ordered_iter(&self) -> std::collections::btree_set::Iter<'_, Arc<BirthdayInfo>> {
         self.schedule.iter()
     }
+
+    /// Finds the entry not linked to a member that goes by the given name
+    pub fn find_unlinked(&self, name: &str) -> Option<&Arc<BirthdayInfo>> {
+        self.birthday_map.values().find(|info| {
+            info.name
+                .as_deref()
+                .map(|own| own.eq_ignore_ascii_case(name))
+                .unwrap_or(false)
+        })
+    }
+
+    /// The lowest ID free for an entry not linked to a member
+    pub fn next_unlinked_id(&self) -> Option<u64> {
+        (1..UNLINKED_ID_LIMIT).find(|id| !self.birthday_map.contains_key(id))
+    }
+}
+
+/// How long a removed birthday can be restored for
+pub const TRASH_RETENTION_DAYS: i64 = 30;
+
+/// Birthdays removed from a guild's schedule, oldest removal first
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct BirthdayTrash {
+    entries: Vec<TrashedBirthday>,
+}
+
+impl BirthdayTrash {
+    /// Adds an entry, replacing any older removal for the same user
+    pub fn push(&mut self, trashed: TrashedBirthday) {
+        self.entries
+            .retain(|entry| entry.info.associated_user != trashed.info.associated_user);
+        self.entries.push(trashed);
+    }
+
+    /// Takes the most recent removal made by the given user
+    pub fn take_latest_by(&mut self, deleted_by: u64) -> Option<TrashedBirthday> {
+        let index = self
+            .entries
+            .iter()
+            .rposition(|entry| entry.deleted_by == deleted_by)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Takes the removed birthday belonging to the given user
+    pub fn take_user(&mut self, user_id: u64) -> Option<TrashedBirthday> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.info.associated_user == user_id)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Takes the user's birthday if it was removed because they left the guild
+    pub fn take_departed(&mut self, user_id: u64) -> Option<TrashedBirthday> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.departed && entry.info.associated_user == user_id)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Drops every entry older than the retention period, returns how many were dropped
+    pub fn purge_expired(&mut self, now: DateTime<Utc>) -> usize {
+        let cutoff = now - Duration::days(TRASH_RETENTION_DAYS);
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.deleted_at > cutoff);
+        before - self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrashedBirthday {
+    pub info: Arc<BirthdayInfo>,
+    pub deleted_at: DateTime<Utc>,
+    pub deleted_by: u64,
+    /// Set when it was removed because the member left, rather than by a command
+    #[serde(default)]
+    pub departed: bool,
 }
 
-#[derive(PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize, Debug)]
+impl TrashedBirthday {
+    /// Rebuilds the original entry, moving it forward a year at a time if it passed while trashed
+    pub fn into_restored(self, now: DateTime<Utc>) -> Arc<BirthdayInfo> {
+        caught_up(self.info, now)
+    }
+}
+
+/// Moves a birthday that was off the schedule forward a year at a time until it is upcoming again
+fn caught_up(info: Arc<BirthdayInfo>, now: DateTime<Utc>) -> Arc<BirthdayInfo> {
+    let mut caught_up = info;
+    while caught_up.datetime < now {
+        caught_up = Arc::new(BirthdayInfo {
+            datetime: caught_up.following_year(None),
+            ..(*caught_up).clone()
+        });
+    }
+    caught_up
+}
+
+/// Every Discord snowflake is above this, so IDs below it are free for entries without a member
+pub const UNLINKED_ID_LIMIT: u64 = 1 << 22;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct BirthdayInfo {
     pub datetime: DateTime<Utc>,
     pub associated_user: u64,
+    /// The timezone the birthday was registered in, missing on entries saved before it was tracked
+    #[serde(default)]
+    #[serde(with = "opt_tz_serde")]
+    pub timezone: Option<Tz>,
+    /// Private birthdays are still announced but left out of exports and feeds
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub year: Option<i32>,
+    /// Only set on entries that are not linked to a member, associated_user is then a local ID
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Set on birthdays on the 29th of February, which are scheduled on the 28th in years
+    /// without one and have to go back to the 29th when there is one again
+    #[serde(default)]
+    pub leap_day: bool,
+}
+
+impl BirthdayInfo {
+    pub fn is_linked(&self) -> bool {
+        self.name.is_none()
+    }
+
+    /// The calendar date of the birthday in its own timezone, or the fallback if it has none.
+    /// Starts are local midnights, so going by the nearest one absorbs the hour older entries
+    /// drifted by when they were moved on a year across a DST change.
+    pub fn local_date(&self, fallback: Option<Tz>) -> NaiveDate {
+        let midday = self.datetime + Duration::hours(12);
+        match self.timezone.or(fallback) {
+            Some(tz) => midday.with_timezone(&tz).date_naive(),
+            None => midday.date_naive(),
+        }
+    }
+
+    /// The month and day the birthday was set to, which is not the scheduled date for the 29th
+    /// of February on years without one
+    pub fn month_day(&self, fallback: Option<Tz>) -> (u32, u32) {
+        if self.leap_day {
+            return (2, 29);
+        }
+        let date = self.local_date(fallback);
+        (date.month(), date.day())
+    }
+
+    /// The start of the same birthday a year on, worked out on the local calendar so a DST
+    /// change between the two years does not move it onto another day
+    pub fn following_year(&self, fallback: Option<Tz>) -> DateTime<Utc> {
+        let (month, day) = self.month_day(fallback);
+        let tz = self.timezone.or(fallback).unwrap_or(Tz::UTC);
+        Self::next_occurrence(month, day, tz, self.datetime + Duration::days(2))
+            .unwrap_or_else(|| delta::shift_years(self.datetime, 1))
+    }
+
+    /// Today's date from the point of view of the birthday's timezone
+    pub fn local_today(&self, fallback: Option<Tz>, now: DateTime<Utc>) -> NaiveDate {
+        match self.timezone.or(fallback) {
+            Some(tz) => now.with_timezone(&tz).date_naive(),
+            None => now.date_naive(),
+        }
+    }
+
+    /// The start of the next (or current, if it is still that day) birthday in the given timezone.
+    /// The 29th of February falls back to the 28th on years without one.
+    pub fn next_occurrence(
+        month: u32,
+        day: u32,
+        tz: Tz,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let local_now = now.with_timezone(&tz);
+        for year in [local_now.year(), local_now.year() + 1] {
+            let date = match NaiveDate::from_ymd_opt(year, month, day) {
+                Some(date) => date,
+                None if month == 2 && day == 29 => NaiveDate::from_ymd_opt(year, 2, 28)?,
+                None => return None,
+            };
+            if date < local_now.date_naive() {
+                continue;
+            }
+            let midnight = date.and_hms_opt(0, 0, 0)?;
+            // Midnight can be skipped by a DST change, the earliest valid instant that day works
+            let start = match tz.from_local_datetime(&midnight).earliest() {
+                Some(start) => start,
+                None => tz
+                    .from_local_datetime(&(midnight + Duration::hours(1)))
+                    .earliest()?,
+            };
+            return Some(start.with_timezone(&Utc));
+        }
+        None
+    }
+}
+
+/// Parses a MM/DD day into its month and day
+pub fn parse_day(day_str: &str) -> Result<(u32, u32), String> {
+    let (month, day) = day_str
+        .trim()
+        .split_once('/')
+        .ok_or_else(|| format!("`{}` is not in MM/DD format", day_str))?;
+    let month = month
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| format!("`{}` is not a valid month", month))?;
+    let day = day
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| format!("`{}` is not a valid day", day))?;
+    // 2000 is a leap year, so the 29th of February is accepted
+    if NaiveDate::from_ymd_opt(2000, month, day).is_none() {
+        return Err(format!(
+            "{:0>2}/{:0>2} is not a day of the year",
+            month, day
+        ));
+    }
+    Ok((month, day))
+}
+
+/// Describes how far away a day is, as in "in 3 days"
+pub fn describe_days_until(days_until: i64) -> String {
+    match days_until {
+        0 => "today".to_string(),
+        1 => "tomorrow".to_string(),
+        n => format!("in {} days", n),
+    }
+}
+
+/// Checks a birth year is one a living person could have
+pub fn validate_year(year: i32) -> Result<(), String> {
+    if year < 1900 || year > Utc::now().year() {
+        return Err(format!("{} is not a plausible birth year", year));
+    }
+    Ok(())
+}
+
+// Only the schedule position identifies an entry, so the other fields do not take part in ordering
+impl PartialEq for BirthdayInfo {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for BirthdayInfo {}
+
+impl Ord for BirthdayInfo {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.datetime
+            .cmp(&other.datetime)
+            .then_with(|| self.associated_user.cmp(&other.associated_user))
+    }
+}
+
+impl PartialOrd for BirthdayInfo {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Context<'a> = poise::Context<'a, Data, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn birthday(user_id: u64, datetime: DateTime<Utc>) -> Arc<BirthdayInfo> {
+        Arc::new(
+            serde_json::from_value(serde_json::json!({
+                "datetime": datetime,
+                "associated_user": user_id,
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn occured_stops_at_the_first_upcoming_birthday() {
+        let now = Utc::now();
+        let mut schedule = BirthdaySchedule::default();
+        let _ = schedule.insert(birthday(1, now - Duration::days(2)));
+        let _ = schedule.insert(birthday(2, now - Duration::hours(1)));
+        let _ = schedule.insert(birthday(3, now + Duration::days(1)));
+
+        // Used to spin forever on the first past birthday, as peeking never moved past it
+        let peeked: Vec<u64> = schedule
+            .peek_occured()
+            .iter()
+            .map(|info| info.associated_user)
+            .collect();
+        assert_eq!(peeked, [1, 2]);
+
+        let popped: Vec<u64> = schedule
+            .pop_occured()
+            .iter()
+            .map(|info| info.associated_user)
+            .collect();
+        assert_eq!(popped, [1, 2]);
+        assert_eq!(schedule.len(), 1);
+        assert!(schedule.peek_occured().is_empty());
+    }
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn upcoming_days(guild: &GuildData, now: DateTime<Utc>) -> Vec<(u64, String, i64)> {
+        guild
+            .upcoming(now, Duration::days(30))
+            .map(|(info, date, days_until)| {
+                (
+                    info.associated_user,
+                    date.format("%m/%d").to_string(),
+                    days_until,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn upcoming_wraps_over_the_new_year() {
+        let mut guild = GuildData::default();
+        let _ = guild
+            .birthday_schedule
+            .insert(birthday(1, utc("2026-12-25T00:00:00Z")));
+        let _ = guild
+            .birthday_schedule
+            .insert(birthday(2, utc("2027-01-03T00:00:00Z")));
+        let _ = guild
+            .birthday_schedule
+            .insert(birthday(3, utc("2027-02-01T00:00:00Z")));
+
+        assert_eq!(
+            upcoming_days(&guild, utc("2026-12-20T12:00:00Z")),
+            [(1, "12/25".to_string(), 5), (2, "01/03".to_string(), 14)]
+        );
+    }
+
+    #[test]
+    fn upcoming_shows_the_29th_of_february_on_the_28th_without_one() {
+        let now = utc("2027-02-10T12:00:00Z");
+        let start = BirthdayInfo::next_occurrence(2, 29, Tz::UTC, now).unwrap();
+        assert_eq!(start, utc("2027-02-28T00:00:00Z"));
+
+        let mut guild = GuildData::default();
+        let _ = guild.birthday_schedule.insert(birthday(1, start));
+        assert_eq!(upcoming_days(&guild, now), [(1, "02/28".to_string(), 18)]);
+    }
+
+    #[test]
+    fn the_29th_of_february_comes_back_after_years_on_the_28th() {
+        let mut info: BirthdayInfo = serde_json::from_value(serde_json::json!({
+            "datetime": "2024-02-29T00:00:00Z",
+            "associated_user": 1,
+            "timezone": "UTC",
+            "leap_day": true,
+        }))
+        .unwrap();
+        let mut dates = vec![];
+        for _ in 0..4 {
+            info = BirthdayInfo {
+                datetime: info.following_year(None),
+                ..info
+            };
+            dates.push(info.datetime);
+        }
+        assert_eq!(
+            dates,
+            [
+                utc("2025-02-28T00:00:00Z"),
+                utc("2026-02-28T00:00:00Z"),
+                utc("2027-02-28T00:00:00Z"),
+                utc("2028-02-29T00:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_dst_change_between_years_keeps_the_local_day() {
+        // The 10th of March is after the change to summer time in 2026 but before it in 2027
+        let info: BirthdayInfo = serde_json::from_value(serde_json::json!({
+            "datetime": "2026-03-10T04:00:00Z",
+            "associated_user": 1,
+            "timezone": "America/New_York",
+        }))
+        .unwrap();
+        assert_eq!(info.following_year(None), utc("2027-03-10T05:00:00Z"));
+
+        // Moved on in UTC as older builds did, it starts at 23:00 on the 9th locally
+        let drifted = BirthdayInfo {
+            datetime: utc("2027-03-10T04:00:00Z"),
+            ..info
+        };
+        assert_eq!(
+            drifted.local_date(None),
+            NaiveDate::from_ymd_opt(2027, 3, 10).unwrap()
+        );
+    }
+}
@@ -0,0 +1,578 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+
+use chrono_tz::Tz;
+use serde_json::{Map, Value};
+use tracing::{info, warn};
+
+use crate::structs::{BirthdayInfo, GuildData, GuildSnapshot, TrashedBirthday};
+
+/// Something wrong with a saved entry and what was done about it
+pub struct Problem {
+    pub guild: String,
+    /// The entry within the guild, None when it is about the guild itself
+    pub entry: Option<String>,
+    pub dropped: bool,
+    pub detail: String,
+}
+
+/// Every problem found while loading, so one bad entry never costs more than itself
+#[derive(Default)]
+pub struct LoadReport {
+    pub problems: Vec<Problem>,
+}
+
+impl LoadReport {
+    fn repaired(&mut self, guild: &str, entry: Option<String>, detail: String) {
+        self.problems.push(Problem {
+            guild: guild.to_string(),
+            entry,
+            dropped: false,
+            detail,
+        });
+    }
+
+    pub fn dropped(&mut self, guild: &str, entry: Option<String>, detail: String) {
+        self.problems.push(Problem {
+            guild: guild.to_string(),
+            entry,
+            dropped: true,
+            detail,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Guilds that were loaded with something repaired or dropped, which only stays fixed once
+    /// they are written back. Guilds that could not be loaded at all are left out, as writing
+    /// them back would delete what is left of them.
+    pub fn repaired_guilds(&self) -> HashSet<u64> {
+        let lost = self
+            .problems
+            .iter()
+            .filter(|problem| problem.dropped && problem.entry.is_none())
+            .map(|problem| problem.guild.as_str())
+            .collect::<HashSet<_>>();
+        self.problems
+            .iter()
+            .filter(|problem| !lost.contains(problem.guild.as_str()))
+            .filter_map(|problem| problem.guild.parse().ok())
+            .collect()
+    }
+
+    /// Logs each problem, then how many there were
+    pub fn log(&self) {
+        if self.is_empty() {
+            return;
+        }
+        for problem in &self.problems {
+            let action = if problem.dropped {
+                "Dropped"
+            } else {
+                "Repaired"
+            };
+            warn!(
+                guild = problem.guild,
+                entry = problem.entry,
+                detail = problem.detail,
+                "{} a bad saved entry",
+                action
+            );
+        }
+        let dropped = self
+            .problems
+            .iter()
+            .filter(|problem| problem.dropped)
+            .count();
+        info!(
+            repaired = self.problems.len() - dropped,
+            dropped, "Finished checking the save"
+        );
+    }
+}
+
+/// Reads the guilds out of a save's guild_map, skipping the ones that cannot be read at all
+pub fn read_guild_map(
+    guilds: Map<String, Value>,
+    report: &mut LoadReport,
+) -> HashMap<u64, GuildSnapshot> {
+    let mut guild_map = HashMap::with_capacity(guilds.len());
+    for (key, mut value) in guilds {
+        let guild_id = match key.parse::<u64>() {
+            Ok(guild_id) => guild_id,
+            Err(e) => {
+                report.dropped(&key, None, format!("Not a guild ID: {}", e));
+                continue;
+            }
+        };
+        let guild_data = match value.get_mut("rw_lock").map(Value::take) {
+            Some(guild_data) => guild_data,
+            None => {
+                report.dropped(&key, None, "The guild has no data".to_string());
+                continue;
+            }
+        };
+        if let Some(rw_lock) = read_guild(&key, guild_data, report) {
+            guild_map.insert(guild_id, GuildSnapshot { rw_lock });
+        }
+    }
+    guild_map
+}
+
+/// Reads one guild, repairing or dropping bad entries and making sure the schedule and the
+/// lookup by user agree. None when the guild itself is unreadable.
+pub fn read_guild(guild: &str, mut value: Value, report: &mut LoadReport) -> Option<GuildData> {
+    let object = match value.as_object_mut() {
+        Some(object) => object,
+        None => {
+            report.dropped(guild, None, "The guild is not a JSON object".to_string());
+            return None;
+        }
+    };
+
+    if let Some(bad) = clear_bad_timezone(object) {
+        report.repaired(
+            guild,
+            None,
+            format!("Unknown default timezone {}, cleared it", bad),
+        );
+    }
+
+    let by_user = read_birthday_map(guild, object.remove("birthday_map"), report);
+    let scheduled = read_schedule(guild, object.remove("schedule"), report);
+    let trash = read_trash(guild, object.remove("trash"), report);
+    let departed = read_birthday_map(guild, object.remove("departed"), report);
+
+    object.insert("schedule".to_string(), Value::Array(Vec::new()));
+    object.insert("birthday_map".to_string(), Value::Object(Map::new()));
+    let mut guild_data = match serde_json::from_value::<GuildData>(value) {
+        Ok(guild_data) => guild_data,
+        Err(e) => {
+            report.dropped(guild, None, e.to_string());
+            return None;
+        }
+    };
+
+    // The lookup by user is the one kept when the two disagree, as it can only hold one entry
+    // per user
+    let mut birthdays = by_user;
+    let scheduled_users: HashSet<u64> = scheduled.iter().map(|info| info.associated_user).collect();
+    for user_id in birthdays
+        .keys()
+        .filter(|user_id| !scheduled_users.contains(user_id))
+    {
+        report.repaired(
+            guild,
+            Some(user_id.to_string()),
+            "In the lookup but missing from the schedule, scheduled it".to_string(),
+        );
+    }
+    for info in scheduled {
+        let user_id = info.associated_user;
+        match birthdays.get(&user_id) {
+            Some(kept) if kept.datetime == info.datetime => {}
+            Some(_) => report.repaired(
+                guild,
+                Some(user_id.to_string()),
+                "Scheduled for a different time than its lookup entry says, kept the lookup entry"
+                    .to_string(),
+            ),
+            None => {
+                report.repaired(
+                    guild,
+                    Some(user_id.to_string()),
+                    "Scheduled but missing from the lookup, added it back".to_string(),
+                );
+                birthdays.insert(user_id, info);
+            }
+        }
+    }
+    for info in birthdays.into_values() {
+        let _ = guild_data.birthday_schedule.insert(Arc::new(info));
+    }
+    for trashed in trash {
+        guild_data.trash.push(trashed);
+    }
+    guild_data.departed = departed
+        .into_iter()
+        .map(|(user_id, info)| (user_id, Arc::new(info)))
+        .collect();
+
+    Some(guild_data)
+}
+
+/// Reads the lookup by user, keyed by the user each entry says it belongs to
+fn read_birthday_map(
+    guild: &str,
+    value: Option<Value>,
+    report: &mut LoadReport,
+) -> HashMap<u64, BirthdayInfo> {
+    let mut birthdays = HashMap::new();
+    let entries = match value {
+        Some(Value::Object(entries)) => entries,
+        None | Some(Value::Null) => return birthdays,
+        Some(_) => {
+            report.dropped(
+                guild,
+                None,
+                "The birthday lookup is not a JSON object".to_string(),
+            );
+            return birthdays;
+        }
+    };
+
+    for (key, value) in entries {
+        let user_id = match key.parse::<u64>() {
+            Ok(user_id) => user_id,
+            Err(e) => {
+                report.dropped(guild, Some(key), format!("Not a user ID: {}", e));
+                continue;
+            }
+        };
+        let info = match read_birthday(guild, &key, value, report) {
+            Some(info) => info,
+            None => continue,
+        };
+        if info.associated_user != user_id {
+            report.repaired(
+                guild,
+                Some(key),
+                format!(
+                    "Filed under the wrong user, moved it to {}",
+                    info.associated_user
+                ),
+            );
+        }
+        birthdays.insert(info.associated_user, info);
+    }
+    birthdays
+}
+
+fn read_schedule(guild: &str, value: Option<Value>, report: &mut LoadReport) -> Vec<BirthdayInfo> {
+    let entries = match value {
+        Some(Value::Array(entries)) => entries,
+        None | Some(Value::Null) => return Vec::new(),
+        Some(_) => {
+            report.dropped(guild, None, "The schedule is not a JSON array".to_string());
+            return Vec::new();
+        }
+    };
+
+    entries
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, value)| {
+            read_birthday(guild, &format!("schedule[{}]", index), value, report)
+        })
+        .collect()
+}
+
+fn read_trash(guild: &str, value: Option<Value>, report: &mut LoadReport) -> Vec<TrashedBirthday> {
+    let entries = match value {
+        Some(Value::Object(mut trash)) => match trash.remove("entries") {
+            Some(Value::Array(entries)) => entries,
+            None | Some(Value::Null) => return Vec::new(),
+            Some(_) => {
+                report.dropped(
+                    guild,
+                    None,
+                    "The trash entries are not a JSON array".to_string(),
+                );
+                return Vec::new();
+            }
+        },
+        None | Some(Value::Null) => return Vec::new(),
+        Some(_) => {
+            report.dropped(guild, None, "The trash is not a JSON object".to_string());
+            return Vec::new();
+        }
+    };
+
+    let mut trashed = Vec::new();
+    for (index, mut value) in entries.into_iter().enumerate() {
+        let entry = format!("trash[{}]", index);
+        if let Some(bad) = value
+            .get_mut("info")
+            .and_then(Value::as_object_mut)
+            .and_then(clear_bad_timezone)
+        {
+            report.repaired(
+                guild,
+                Some(entry.clone()),
+                format!("Unknown timezone {}, cleared it", bad),
+            );
+        }
+        match serde_json::from_value::<TrashedBirthday>(value) {
+            Ok(entry) => trashed.push(entry),
+            Err(e) => report.dropped(guild, Some(entry), e.to_string()),
+        }
+    }
+    trashed
+}
+
+fn read_birthday(
+    guild: &str,
+    entry: &str,
+    mut value: Value,
+    report: &mut LoadReport,
+) -> Option<BirthdayInfo> {
+    if let Some(bad) = value.as_object_mut().and_then(clear_bad_timezone) {
+        report.repaired(
+            guild,
+            Some(entry.to_string()),
+            format!("Unknown timezone {}, cleared it", bad),
+        );
+    }
+    match serde_json::from_value::<BirthdayInfo>(value) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            report.dropped(guild, Some(entry.to_string()), e.to_string());
+            None
+        }
+    }
+}
+
+/// Clears a timezone chrono-tz no longer knows, such as after a rename in its database, and
+/// returns what it was
+fn clear_bad_timezone(object: &mut Map<String, Value>) -> Option<String> {
+    let name = object.get("timezone")?.as_str()?;
+    if Tz::from_str(name).is_ok() {
+        return None;
+    }
+    let name = name.to_string();
+    object.insert("timezone".to_string(), Value::Null);
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const USER: u64 = 300000000000000000;
+    const OTHER: u64 = 400000000000000000;
+
+    fn birthday(user: u64, datetime: &str) -> Value {
+        json!({ "datetime": datetime, "associated_user": user })
+    }
+
+    fn guild(fields: Value) -> Value {
+        let mut guild = json!({
+            "announcement_channel": null,
+            "schedule": [birthday(USER, "2027-03-01T00:00:00Z")],
+            "birthday_map": { USER.to_string(): birthday(USER, "2027-03-01T00:00:00Z") },
+        });
+        for (key, value) in fields.as_object().unwrap() {
+            guild[key] = value.clone();
+        }
+        guild
+    }
+
+    fn read(value: Value) -> (Option<GuildData>, LoadReport) {
+        let mut report = LoadReport::default();
+        let guild_data = read_guild("100", value, &mut report);
+        (guild_data, report)
+    }
+
+    /// The one problem reported, as whether it was dropped and which entry it was about
+    fn only_problem(report: &LoadReport) -> (bool, Option<&str>) {
+        assert_eq!(report.problems.len(), 1, "{:?}", details(report));
+        let problem = &report.problems[0];
+        (problem.dropped, problem.entry.as_deref())
+    }
+
+    fn details(report: &LoadReport) -> Vec<&str> {
+        report
+            .problems
+            .iter()
+            .map(|problem| problem.detail.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn a_clean_guild_has_no_problems() {
+        let (guild_data, report) = read(guild(json!({})));
+        assert!(report.is_empty());
+        assert!(guild_data.unwrap().birthday_schedule.get(USER).is_some());
+    }
+
+    #[test]
+    fn a_guild_that_is_not_an_object_is_dropped() {
+        let (guild_data, report) = read(json!([]));
+        assert!(guild_data.is_none());
+        assert_eq!(only_problem(&report), (true, None));
+    }
+
+    #[test]
+    fn a_guild_with_unreadable_settings_is_dropped() {
+        let (guild_data, report) = read(guild(json!({ "announcement_channel": "general" })));
+        assert!(guild_data.is_none());
+        assert_eq!(only_problem(&report), (true, None));
+    }
+
+    #[test]
+    fn an_unknown_default_timezone_is_cleared() {
+        let (guild_data, report) = read(guild(json!({ "timezone": "Mars/Olympus_Mons" })));
+        assert_eq!(guild_data.unwrap().timezone, None);
+        assert_eq!(only_problem(&report), (false, None));
+    }
+
+    #[test]
+    fn an_unknown_birthday_timezone_is_cleared() {
+        let mut bday = birthday(USER, "2027-03-01T00:00:00Z");
+        bday["timezone"] = json!("Mars/Olympus_Mons");
+        let (guild_data, report) = read(guild(json!({
+            "schedule": [bday.clone()],
+            "birthday_map": { USER.to_string(): bday },
+        })));
+        let guild_data = guild_data.unwrap();
+        assert_eq!(
+            guild_data.birthday_schedule.get(USER).unwrap().timezone,
+            None
+        );
+        // Once for the schedule and once for the lookup
+        assert_eq!(report.problems.len(), 2);
+        assert!(report.problems.iter().all(|problem| !problem.dropped));
+    }
+
+    #[test]
+    fn a_lookup_that_is_not_an_object_is_dropped_and_rebuilt_from_the_schedule() {
+        let (guild_data, report) = read(guild(json!({ "birthday_map": [] })));
+        assert!(guild_data.unwrap().birthday_schedule.get(USER).is_some());
+        assert_eq!(report.problems.len(), 2);
+        assert!(report.problems[0].dropped);
+        assert!(!report.problems[1].dropped);
+    }
+
+    #[test]
+    fn a_lookup_key_that_is_not_a_user_id_is_dropped() {
+        let (guild_data, report) = read(guild(json!({
+            "schedule": [],
+            "birthday_map": { "someone": birthday(USER, "2027-03-01T00:00:00Z") },
+        })));
+        assert!(guild_data.unwrap().birthday_schedule.is_empty());
+        assert_eq!(only_problem(&report), (true, Some("someone")));
+    }
+
+    #[test]
+    fn an_unreadable_birthday_is_dropped() {
+        let (guild_data, report) = read(guild(json!({
+            "schedule": [],
+            "birthday_map": { USER.to_string(): { "datetime": "yesterday" } },
+        })));
+        assert!(guild_data.unwrap().birthday_schedule.is_empty());
+        assert_eq!(
+            only_problem(&report),
+            (true, Some(USER.to_string().as_str()))
+        );
+    }
+
+    #[test]
+    fn a_birthday_filed_under_the_wrong_user_is_moved() {
+        let (guild_data, report) = read(guild(json!({
+            "schedule": [birthday(OTHER, "2027-03-01T00:00:00Z")],
+            "birthday_map": { USER.to_string(): birthday(OTHER, "2027-03-01T00:00:00Z") },
+        })));
+        let guild_data = guild_data.unwrap();
+        assert!(guild_data.birthday_schedule.get(USER).is_none());
+        assert!(guild_data.birthday_schedule.get(OTHER).is_some());
+        assert_eq!(
+            only_problem(&report),
+            (false, Some(USER.to_string().as_str()))
+        );
+    }
+
+    #[test]
+    fn a_schedule_that_is_not_an_array_is_dropped_and_rebuilt_from_the_lookup() {
+        let (guild_data, report) = read(guild(json!({ "schedule": {} })));
+        assert!(guild_data.unwrap().birthday_schedule.get(USER).is_some());
+        assert_eq!(report.problems.len(), 2);
+        assert!(report.problems[0].dropped);
+        assert!(!report.problems[1].dropped);
+    }
+
+    #[test]
+    fn a_lookup_entry_missing_from_the_schedule_is_scheduled() {
+        let (guild_data, report) = read(guild(json!({ "schedule": [] })));
+        assert_eq!(guild_data.unwrap().birthday_schedule.len(), 1);
+        assert_eq!(
+            only_problem(&report),
+            (false, Some(USER.to_string().as_str()))
+        );
+    }
+
+    #[test]
+    fn a_scheduled_entry_missing_from_the_lookup_is_added_back() {
+        let (guild_data, report) = read(guild(json!({ "birthday_map": {} })));
+        assert!(guild_data.unwrap().birthday_schedule.get(USER).is_some());
+        assert_eq!(
+            only_problem(&report),
+            (false, Some(USER.to_string().as_str()))
+        );
+    }
+
+    #[test]
+    fn the_lookup_wins_when_the_schedule_disagrees() {
+        let (guild_data, report) = read(guild(json!({
+            "schedule": [birthday(USER, "2027-05-01T00:00:00Z")],
+        })));
+        let guild_data = guild_data.unwrap();
+        let kept = guild_data.birthday_schedule.get(USER).unwrap();
+        assert_eq!(kept.datetime.to_rfc3339(), "2027-03-01T00:00:00+00:00");
+        assert_eq!(guild_data.birthday_schedule.len(), 1);
+        assert_eq!(
+            only_problem(&report),
+            (false, Some(USER.to_string().as_str()))
+        );
+    }
+
+    #[test]
+    fn a_trash_that_is_not_an_object_is_dropped() {
+        let (guild_data, report) = read(guild(json!({ "trash": [] })));
+        assert!(guild_data.unwrap().trash.is_empty());
+        assert_eq!(only_problem(&report), (true, None));
+    }
+
+    #[test]
+    fn trash_entries_that_are_not_an_array_are_dropped() {
+        let (guild_data, report) = read(guild(json!({ "trash": { "entries": {} } })));
+        assert!(guild_data.unwrap().trash.is_empty());
+        assert_eq!(only_problem(&report), (true, None));
+    }
+
+    #[test]
+    fn an_unreadable_trash_entry_is_dropped_and_a_bad_timezone_in_one_is_cleared() {
+        let mut info = birthday(OTHER, "2027-06-15T00:00:00Z");
+        info["timezone"] = json!("Mars/Olympus_Mons");
+        let (guild_data, report) = read(guild(json!({
+            "trash": { "entries": [
+                { "info": info, "deleted_at": "2026-10-01T12:00:00Z", "deleted_by": OTHER },
+                { "deleted_by": OTHER },
+            ] },
+        })));
+        assert_eq!(guild_data.unwrap().trash.len(), 1);
+        assert_eq!(report.problems.len(), 2);
+        assert!(!report.problems[0].dropped);
+        assert_eq!(report.problems[0].entry.as_deref(), Some("trash[0]"));
+        assert!(report.problems[1].dropped);
+        assert_eq!(report.problems[1].entry.as_deref(), Some("trash[1]"));
+    }
+
+    #[test]
+    fn departed_birthdays_are_checked_like_the_lookup() {
+        let (guild_data, report) = read(guild(json!({
+            "departed": {
+                OTHER.to_string(): birthday(OTHER, "2027-06-15T00:00:00Z"),
+                "someone": birthday(OTHER, "2027-06-15T00:00:00Z"),
+            },
+        })));
+        assert!(guild_data.unwrap().departed.contains_key(&OTHER));
+        assert_eq!(only_problem(&report), (true, Some("someone")));
+    }
+}
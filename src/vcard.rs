@@ -0,0 +1,133 @@
+use chrono::{Datelike, NaiveDate};
+
+use crate::{
+    ical::{split_property, unescape_text, unfold_lines},
+    records::BirthdayRecord,
+    structs::validate_year,
+};
+
+/// Reads the birthdays out of a vCard file, contacts without a BDAY field are skipped
+pub fn read_vcards(text: &str) -> Vec<Result<BirthdayRecord, String>> {
+    let mut res = vec![];
+    let mut card: Option<Card> = None;
+
+    for line in unfold_lines(text) {
+        let (name, params, value) = match split_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+        // Properties can be grouped, as in "item1.BDAY"
+        let name = name.rsplit('.').next().unwrap_or_default().to_string();
+        match (name.as_str(), card.as_mut()) {
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VCARD") => card = Some(Card::default()),
+            ("FN", Some(card)) => card.formatted_name = Some(unescape_text(value)),
+            ("N", Some(card)) => card.structured_name = Some(structured_name(value)),
+            ("BDAY", Some(card)) => card.birthday = Some((params.to_string(), value.to_string())),
+            ("END", Some(_)) if value.eq_ignore_ascii_case("VCARD") => {
+                if let Some(card) = card.take() {
+                    if let Some(record) = card.into_record() {
+                        res.push(record);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+#[derive(Default)]
+struct Card {
+    formatted_name: Option<String>,
+    structured_name: Option<String>,
+    birthday: Option<(String, String)>,
+}
+
+impl Card {
+    fn into_record(self) -> Option<Result<BirthdayRecord, String>> {
+        let (params, value) = self.birthday?;
+        let name = self
+            .formatted_name
+            .or(self.structured_name)
+            .filter(|name| !name.trim().is_empty());
+        let name = match name {
+            Some(name) => name.trim().to_string(),
+            None => {
+                return Some(Err(format!(
+                    "Contact with birthday `{}` has no name",
+                    value
+                )))
+            }
+        };
+        if params.to_uppercase().contains("VALUE=TEXT") {
+            return Some(Err(format!(
+                "{}'s birthday `{}` is free text, not a date",
+                name, value
+            )));
+        }
+
+        Some(match parse_bday(&value) {
+            Some((month, day, year)) => Ok(BirthdayRecord {
+                user: name,
+                date: format!("{:0>2}/{:0>2}", month, day),
+                timezone: None,
+                // Some address books store a placeholder year (like 1604) when none is known
+                year: year.filter(|year| validate_year(*year).is_ok()),
+            }),
+            None => Err(format!("{}'s birthday `{}` is not a date", name, value)),
+        })
+    }
+}
+
+/// Turns "Family;Given;Middle;Prefix;Suffix" into "Given Family"
+fn structured_name(value: &str) -> String {
+    let parts = value.split(';').map(unescape_text).collect::<Vec<_>>();
+    let family = parts.first().map(String::as_str).unwrap_or_default();
+    let given = parts.get(1).map(String::as_str).unwrap_or_default();
+    format!("{} {}", given, family).trim().to_string()
+}
+
+/// Parses the date forms used by vCard 3 and 4, returning the month, day and year if there is one
+fn parse_bday(value: &str) -> Option<(u32, u32, Option<i32>)> {
+    // A time of day can follow the date, it does not matter here
+    let date = value.trim().split('T').next()?;
+
+    if let Some(without_year) = date.strip_prefix("--") {
+        let digits = without_year.replace('-', "");
+        // Checked as ASCII digits first so the slicing below cannot land inside a character
+        if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let month = digits[..2].parse().ok()?;
+        let day = digits[2..].parse().ok()?;
+        // Leap year so the 29th of February is accepted
+        NaiveDate::from_ymd_opt(2000, month, day)?;
+        return Some((month, day, None));
+    }
+
+    let digits = date.replace('-', "");
+    let parsed = NaiveDate::parse_from_str(&digits, "%Y%m%d").ok()?;
+    Some((parsed.month(), parsed.day(), Some(parsed.year())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_dates_with_and_without_a_year() {
+        assert_eq!(parse_bday("1990-05-04"), Some((5, 4, Some(1990))));
+        assert_eq!(parse_bday("19900504T120000Z"), Some((5, 4, Some(1990))));
+        assert_eq!(parse_bday("--0229"), Some((2, 29, None)));
+        assert_eq!(parse_bday("--12-31"), Some((12, 31, None)));
+    }
+
+    #[test]
+    fn rejects_non_ascii_without_panicking() {
+        // Four bytes, but the euro sign is three of them
+        assert_eq!(parse_bday("--€1"), None);
+        assert_eq!(parse_bday("--1€"), None);
+        assert_eq!(parse_bday("--ab12"), None);
+        assert_eq!(parse_bday("--1332"), None);
+    }
+}
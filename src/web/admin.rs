@@ -0,0 +1,345 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use serenity::model::id::ChannelId;
+
+use crate::{
+    channels::validate_announcement_channel,
+    mutations::{
+        remove_birthday, set_announcement_channel, set_birthday, set_timezone, BirthdayRequest,
+        MutationError,
+    },
+    structs::{GuildData, UNLINKED_ID_LIMIT},
+};
+
+use super::{feeds::tokens_match, WebState};
+
+/// Removals made through the API are not tied to a Discord user, so nobody can `/bday undo` them
+const ADMIN_DELETER_ID: u64 = 0;
+
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<MutationError> for ApiError {
+    fn from(e: MutationError) -> Self {
+        let status = match e {
+            MutationError::UnknownGuild | MutationError::NotRegistered => StatusCode::NOT_FOUND,
+            MutationError::NotSaved(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        ApiError(status, e.to_string())
+    }
+}
+
+/// Rejects any request without the configured bearer token
+pub async fn require_token<B>(
+    State(token): State<Arc<String>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match given {
+        Some(given) if tokens_match(&token, given) => next.run(request).await,
+        _ => ApiError(
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong token".to_string(),
+        )
+        .into_response(),
+    }
+}
+
+#[derive(Serialize)]
+pub struct GuildSummary {
+    id: u64,
+    birthdays: usize,
+    timezone: Option<String>,
+    announcement_channel: Option<u64>,
+}
+
+pub async fn list_guilds(State(web_state): State<Arc<WebState>>) -> Json<Vec<GuildSummary>> {
+    let reader = web_state.state.guild_map.read().await;
+    let mut res = Vec::with_capacity(reader.len());
+    for (guild_id, guild_data) in reader.iter() {
+        let guild_data = guild_data.rw_lock.read().await;
+        res.push(GuildSummary {
+            id: *guild_id,
+            birthdays: guild_data.birthday_schedule.len(),
+            timezone: guild_data.timezone.map(|tz| tz.name().to_string()),
+            announcement_channel: guild_data.announcement_channel,
+        });
+    }
+    res.sort_by_key(|summary| summary.id);
+    Json(res)
+}
+
+pub async fn get_guild(
+    State(web_state): State<Arc<WebState>>,
+    Path(guild_id): Path<u64>,
+) -> Result<Json<GuildData>, ApiError> {
+    let reader = web_state.state.guild_map.read().await;
+    let guild_data = reader.get(&guild_id).ok_or(MutationError::UnknownGuild)?;
+    let guild_data = guild_data.rw_lock.read().await.clone();
+    Ok(Json(guild_data))
+}
+
+/// Tells a missing field (leave alone) apart from an explicit null (clear)
+fn double_option<'de, D, T>(de: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Deserialize::deserialize(de).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct GuildPatch {
+    #[serde(default, deserialize_with = "double_option")]
+    timezone: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    announcement_channel: Option<Option<u64>>,
+}
+
+pub async fn patch_guild(
+    State(web_state): State<Arc<WebState>>,
+    Path(guild_id): Path<u64>,
+    Json(patch): Json<GuildPatch>,
+) -> Result<Json<GuildData>, ApiError> {
+    if let Some(timezone) = patch.timezone {
+        set_timezone(
+            &web_state.state,
+            web_state.storage.as_ref(),
+            guild_id,
+            timezone.as_deref(),
+        )
+        .await?;
+    }
+    if let Some(channel_id) = patch.announcement_channel {
+        // Checked the same way /channel does, so the bot is never pointed at a channel it
+        // cannot post in
+        if let Some(channel_id) = channel_id {
+            let bot = web_state.http.get_current_user().await.map_err(|e| {
+                ApiError(
+                    StatusCode::BAD_GATEWAY,
+                    format!("Could not reach Discord: {}", e),
+                )
+            })?;
+            validate_announcement_channel(&web_state.http, guild_id, bot.id, ChannelId(channel_id))
+                .await
+                .map_err(|e| ApiError(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+        }
+        set_announcement_channel(
+            &web_state.state,
+            web_state.storage.as_ref(),
+            guild_id,
+            channel_id,
+        )
+        .await?;
+    }
+    get_guild(State(web_state), Path(guild_id)).await
+}
+
+#[derive(Deserialize)]
+pub struct BirthdayBody {
+    /// MM/DD
+    date: String,
+    timezone: Option<String>,
+    year: Option<i32>,
+    #[serde(default)]
+    private: bool,
+}
+
+pub async fn put_birthday(
+    State(web_state): State<Arc<WebState>>,
+    Path((guild_id, user_id)): Path<(u64, u64)>,
+    Json(body): Json<BirthdayBody>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Lower IDs belong to entries without a member and can only be made by an import
+    if user_id < UNLINKED_ID_LIMIT {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("{} is not a Discord user ID", user_id),
+        ));
+    }
+    let request = BirthdayRequest {
+        user_id,
+        day: body.date,
+        timezone: body.timezone,
+        private: body.private,
+        year: body.year,
+    };
+    let entry = set_birthday(
+        &web_state.state,
+        web_state.storage.as_ref(),
+        guild_id,
+        request,
+    )
+    .await?;
+    Ok(Json((*entry).clone()))
+}
+
+pub async fn delete_birthday(
+    State(web_state): State<Arc<WebState>>,
+    Path((guild_id, user_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let removed = remove_birthday(
+        &web_state.state,
+        web_state.storage.as_ref(),
+        guild_id,
+        user_id,
+        ADMIN_DELETER_ID,
+    )
+    .await?;
+    Ok(Json((*removed).clone()))
+}
+
+/// Writes any pending changes before answering
+pub async fn trigger_save(State(web_state): State<Arc<WebState>>) -> Result<StatusCode, ApiError> {
+    web_state
+        .storage
+        .flush()
+        .await
+        .map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+
+    use axum::{http::Uri, Router};
+    use poise::BoxFuture;
+    use serenity::http::HttpBuilder;
+
+    use super::*;
+    use crate::{
+        health::Health,
+        metrics::Metrics,
+        names::NameResolver,
+        storage::Storage,
+        structs::{ApplicationState, RWGuildData},
+    };
+
+    const GUILD: u64 = 100;
+
+    struct NoStorage;
+
+    impl Storage for NoStorage {
+        fn mark_dirty(&self, _guild_id: u64) {}
+
+        fn flush(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn persist(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// Stands in for Discord, where every channel is a voice channel in GUILD
+    async fn fake_discord(uri: Uri) -> Json<serde_json::Value> {
+        if uri.path().ends_with("/users/@me") {
+            return Json(json!({
+                "id": "1",
+                "username": "origin-bot",
+                "discriminator": "0000",
+                "avatar": null,
+                "bot": true,
+                "mfa_enabled": false,
+            }));
+        }
+        let channel_id = uri.path().rsplit('/').next().unwrap().to_string();
+        Json(json!({
+            "id": channel_id,
+            "guild_id": GUILD.to_string(),
+            "type": 2,
+            "name": "Voice",
+        }))
+    }
+
+    async fn web_state() -> Arc<WebState> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().fallback(fake_discord);
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let http = HttpBuilder::new("token")
+            .proxy(format!("http://{}", address))
+            .unwrap()
+            .ratelimiter_disabled(true)
+            .build();
+
+        let state = ApplicationState::default();
+        let guild_data = GuildData {
+            announcement_channel: Some(200),
+            ..Default::default()
+        };
+        state
+            .guild_map
+            .write()
+            .await
+            .insert(GUILD, Arc::new(RWGuildData::from(guild_data)));
+        Arc::new(WebState {
+            state: Arc::new(state),
+            storage: Arc::new(NoStorage),
+            http: Arc::new(http),
+            names: Arc::new(NameResolver::default()),
+            metrics: Arc::new(Metrics::new()),
+            health: Arc::new(Health::new(Duration::from_secs(900))),
+        })
+    }
+
+    #[tokio::test]
+    async fn a_channel_the_bot_cannot_post_in_is_refused() {
+        let web_state = web_state().await;
+        let patch: GuildPatch =
+            serde_json::from_value(json!({ "announcement_channel": 300 })).unwrap();
+
+        let res = patch_guild(State(Arc::clone(&web_state)), Path(GUILD), Json(patch)).await;
+        let Err(ApiError(status, error)) = res else {
+            panic!("the channel was accepted");
+        };
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(
+            error.contains("not a text or announcement channel"),
+            "{}",
+            error
+        );
+
+        let guild_data = get_guild(State(web_state), Path(GUILD)).await.ok().unwrap();
+        assert_eq!(guild_data.announcement_channel, Some(200));
+    }
+
+    #[tokio::test]
+    async fn clearing_the_channel_needs_no_check() {
+        let web_state = web_state().await;
+        let patch: GuildPatch =
+            serde_json::from_value(json!({ "announcement_channel": null })).unwrap();
+
+        let guild_data = patch_guild(State(web_state), Path(GUILD), Json(patch))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(guild_data.announcement_channel, None);
+    }
+}
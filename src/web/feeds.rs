@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{Duration, Utc};
+use chrono_tz::Tz;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+
+use crate::{
+    ical::{write_calendar, CalendarEntry},
+    names::MemberName,
+    structs::{describe_days_until, BirthdayInfo},
+};
+
+use super::WebState;
+
+const TOKEN_LENGTH: usize = 40;
+const DEFAULT_RSS_DAYS: i64 = 30;
+const MAX_RSS_DAYS: i64 = 366;
+
+/// Makes a new unguessable feed token
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// The calendar and RSS URLs of a guild's feeds
+pub fn feed_urls(base_url: &str, guild_id: u64, token: &str) -> (String, String) {
+    let base = format!(
+        "{}/feeds/{}/{}",
+        base_url.trim_end_matches('/'),
+        guild_id,
+        token
+    );
+    (
+        format!("{}/birthdays.ics", base),
+        format!("{}/upcoming.rss", base),
+    )
+}
+
+/// Compares without bailing out early so the token cannot be guessed a character at a time
+pub fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Copies out what a feed needs, answering not found for unknown guilds and wrong tokens alike
+async fn feed_entries(
+    web_state: &WebState,
+    guild_id: u64,
+    token: &str,
+    window: Option<Duration>,
+) -> Result<(Vec<(Arc<BirthdayInfo>, i64)>, Option<Tz>), StatusCode> {
+    let reader = web_state.state.guild_map.read().await;
+    let guild_data = reader
+        .get(&guild_id)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .rw_lock
+        .read()
+        .await;
+
+    match &guild_data.feed_token {
+        Some(expected) if tokens_match(expected, token) => {}
+        _ => return Err(StatusCode::NOT_FOUND),
+    }
+
+    let entries = match window {
+        Some(window) => guild_data
+            .upcoming(Utc::now(), window)
+            .filter(|(info, _, _)| !info.private)
+            .map(|(info, _, days_until)| (info, days_until))
+            .collect(),
+        None => guild_data
+            .birthday_schedule
+            .ordered_iter()
+            .filter(|info| !info.private)
+            .map(|info| (Arc::clone(info), 0))
+            .collect(),
+    };
+    Ok((entries, guild_data.timezone))
+}
+
+pub async fn calendar(
+    State(web_state): State<Arc<WebState>>,
+    Path((guild_id, token)): Path<(u64, String)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let (entries, guild_timezone) = feed_entries(&web_state, guild_id, &token, None).await?;
+
+    let names = web_state
+        .names
+        .resolve_entries(
+            &web_state.http,
+            guild_id,
+            entries.iter().map(|(info, _)| info.as_ref()),
+        )
+        .await;
+
+    let calendar_entries = entries
+        .iter()
+        .filter_map(|(info, _)| match &names[&info.associated_user] {
+            MemberName::Departed => None,
+            name => CalendarEntry::for_birthday(guild_id, info, guild_timezone, name.display()),
+        })
+        .collect::<Vec<_>>();
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        write_calendar("Birthdays", &calendar_entries, Utc::now()),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct RssQuery {
+    days: Option<i64>,
+}
+
+pub async fn rss(
+    State(web_state): State<Arc<WebState>>,
+    Path((guild_id, token)): Path<(u64, String)>,
+    Query(query): Query<RssQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let days = query
+        .days
+        .unwrap_or(DEFAULT_RSS_DAYS)
+        .clamp(1, MAX_RSS_DAYS);
+    let (entries, guild_timezone) =
+        feed_entries(&web_state, guild_id, &token, Some(Duration::days(days))).await?;
+
+    let names = web_state
+        .names
+        .resolve_entries(
+            &web_state.http,
+            guild_id,
+            entries.iter().map(|(info, _)| info.as_ref()),
+        )
+        .await;
+
+    let mut items = String::new();
+    for (info, days_until) in entries.iter() {
+        let name = match &names[&info.associated_user] {
+            MemberName::Departed => continue,
+            name => name.display(),
+        };
+        let local_date = info.local_date(guild_timezone);
+        items += &format!(
+            "<item><title>{}</title><description>{}</description><guid isPermaLink=\"false\">{}-{}-{}</guid></item>",
+            escape_xml(&format!("{}'s birthday is {}", name, describe_days_until(*days_until))),
+            escape_xml(&local_date.format("%B %e").to_string()),
+            guild_id,
+            info.associated_user,
+            local_date.format("%Y")
+        );
+    }
+
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\"><channel><title>Upcoming birthdays</title><link>https://discord.com/channels/{}</link><description>Birthdays in the next {} days</description>{}</channel></rss>\n",
+        guild_id, days, items
+    );
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")],
+        body,
+    ))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,150 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::{get, post, put},
+    Json, Router,
+};
+use serenity::http::Http;
+use tracing::info;
+
+use crate::{
+    health::Health, metrics::Metrics, names::NameResolver, storage::Storage,
+    structs::ApplicationState,
+};
+
+pub mod admin;
+pub mod feeds;
+
+/// What the request handlers can see, they only ever take read locks on the state outside of
+/// the shared mutation functions
+pub struct WebState {
+    pub state: Arc<ApplicationState>,
+    pub storage: Arc<dyn Storage>,
+    pub http: Arc<Http>,
+    pub names: Arc<NameResolver>,
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<Health>,
+}
+
+/// Which listeners to start, each one stays off unless its port is given
+#[derive(Default)]
+pub struct WebSettings {
+    pub feed_port: Option<u16>,
+    /// Address the feeds are reachable at from outside, used when handing out feed links
+    pub public_url: Option<String>,
+    pub admin_port: Option<u16>,
+    pub admin_token: Option<String>,
+}
+
+impl WebSettings {
+    /// Where feed links point to, None when the feed server is not running
+    pub fn feed_base_url(&self) -> Option<String> {
+        self.feed_port.map(|port| {
+            self.public_url
+                .clone()
+                .unwrap_or_else(|| format!("http://localhost:{}", port))
+        })
+    }
+}
+
+/// Serves the per-guild calendar and RSS feeds until the listener fails
+pub async fn serve_feeds(port: u16, web_state: Arc<WebState>) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route(
+            "/feeds/:guild_id/:token/birthdays.ics",
+            get(feeds::calendar),
+        )
+        .route("/feeds/:guild_id/:token/upcoming.rss", get(feeds::rss))
+        .with_state(web_state);
+
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    info!(%address, "Serving feeds");
+    axum::Server::try_bind(&address)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Serves metrics, health checks and, when a token is configured, the admin API on localhost only
+pub async fn serve_admin(
+    port: u16,
+    token: Option<String>,
+    web_state: Arc<WebState>,
+) -> anyhow::Result<()> {
+    let mut app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/health", get(health))
+        .route("/ready", get(ready));
+
+    // Every API request needs the admin token, without one the API is not mounted at all
+    if let Some(token) = token {
+        let api = Router::new()
+            .route("/api/guilds", get(admin::list_guilds))
+            .route(
+                "/api/guilds/:guild_id",
+                get(admin::get_guild).patch(admin::patch_guild),
+            )
+            .route(
+                "/api/guilds/:guild_id/birthdays/:user_id",
+                put(admin::put_birthday).delete(admin::delete_birthday),
+            )
+            .route("/api/save", post(admin::trigger_save))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::new(token),
+                admin::require_token,
+            ));
+        app = app.merge(api);
+    }
+
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    info!(%address, "Serving admin endpoints");
+    axum::Server::try_bind(&address)?
+        .serve(app.with_state(web_state).into_make_service())
+        .await?;
+    Ok(())
+}
+
+async fn metrics(State(web_state): State<Arc<WebState>>) -> impl IntoResponse {
+    // The state sizes are cheap to count, so they are refreshed on every scrape
+    let (guilds, birthdays) = {
+        let reader = web_state.state.guild_map.read().await;
+        let mut birthdays = 0;
+        for guild_data in reader.values() {
+            birthdays += guild_data.rw_lock.read().await.birthday_schedule.len();
+        }
+        (reader.len(), birthdays)
+    };
+    web_state.metrics.guilds.set(guilds as i64);
+    web_state.metrics.birthdays.set(birthdays as i64);
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        web_state.metrics.render(),
+    )
+}
+
+/// Whether every background task is still running, with the details of each
+async fn health(State(web_state): State<Arc<WebState>>) -> impl IntoResponse {
+    let report = web_state.health.report();
+    let status = if report.live {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Whether the bot is connected and announcing, on top of being alive
+async fn ready(State(web_state): State<Arc<WebState>>) -> impl IntoResponse {
+    let report = web_state.health.report();
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}